@@ -7,7 +7,7 @@ use url::Url;
 
 fn main() {
     let url = Url::parse("ws://echo.websocket.org").unwrap();
-    let mut ws = WebSocket::with_options(url, 13, Some(&["chat", "superchat"][..]), None);
+    let mut ws = WebSocket::with_options(url, 13, Some(&["chat", "superchat"][..]), None, None);
     ws.connect().unwrap();
 
     let msg = WSMessage::text("Hello, World!"); //.mask();
@@ -0,0 +1,22 @@
+extern crate websocket;
+
+use websocket::WebSocketServer;
+
+fn main() {
+    let server = WebSocketServer::bind("127.0.0.1:9001").unwrap().protocols(&["chat"]);
+
+    for conn in server.incoming() {
+        let mut ws = match conn {
+            Ok(ws) => ws,
+            Err(e) => { println!("handshake failed: {:?}", e); continue }
+        };
+
+        // Echo everything back, server frames are never masked
+        while let Ok(msg) = ws.read_message() {
+            println!("{:?} {:?}", msg, msg.to_string());
+            if ws.send_message(&msg.unmask()).is_err() {
+                break;
+            }
+        }
+    }
+}
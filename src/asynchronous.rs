@@ -0,0 +1,485 @@
+use std::io;
+use std::error::FromError;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use url::Url;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(feature = "futures")] use futures::{Stream, Sink};
+
+use nonce::Nonce;
+use message::WSMessage;
+#[cfg(feature = "futures")] use message::{WSStatusCode, WS_FIN};
+use error::{WSError, WSResult};
+#[cfg(feature = "futures")] use socket::{DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_FRAGMENT_SIZE};
+use codec::FrameCodec;
+use handshake::{self, RequestHeaders, Response, ResponseParser};
+
+macro_rules! try_poll {
+    ($e:expr) => (match $e {
+        Poll::Ready(Ok(v)) => v,
+        Poll::Ready(Err(e)) => return Poll::Ready(Err(FromError::from_error(e))),
+        Poll::Pending => return Poll::Pending
+    })
+}
+
+// Async client over tokio transports, uses the same FrameCodec
+// as blocking WebSocket. Masking is up to caller, as with `WebSocket::send_message()`.
+pub struct AsyncWebSocket<S = TcpStream> {
+    stream: S,
+    pub url: Url,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    codec: FrameCodec,
+    response: Option<Response>,
+    protocol: Option<String>,
+    // set after fatal read error, nothing more is read then
+    failed: bool
+}
+
+impl AsyncWebSocket<TcpStream> {
+    // Dials TCP and performs handshake, wss:// requires TLS stream set up by caller,
+    // which can be passed to `from_stream()`
+    pub fn connect(url: Url) -> Connect {
+        let addr = format!("{}:{}", url.serialize_host().unwrap_or(String::new()), url.port().unwrap_or(80));
+        Connect {
+            tcp: if &*url.scheme == "ws" { Some(Box::pin(TcpStream::connect(addr))) } else { None },
+            url: Some(url),
+            handshake: None
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWebSocket<S> {
+    #[inline] pub fn from_stream(stream: S, url: Url) -> Handshake<S> {
+        AsyncWebSocket::from_stream_with_options(stream, url, 13, None, RequestHeaders::new())
+    }
+
+    // Performs client handshake over already connected transport
+    pub fn from_stream_with_options(stream: S, url: Url, version: u32, protocols: Option<&[&str]>, request: RequestHeaders) -> Handshake<S> {
+        let nonce = Nonce::new();
+        let protocols = protocols.map(|v| v.iter().map(|p| p.to_string()).collect()).unwrap_or(Vec::new());
+
+        let mut ws = AsyncWebSocket {
+            stream: stream,
+            url: url,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            codec: FrameCodec::new(false),
+            response: None,
+            protocol: None,
+            failed: false
+        };
+
+        let res = handshake::write_request(&mut ws.write_buf, &ws.url, &*nonce, version, &*protocols, &[], &request);
+        Handshake {
+            ws: Some(ws),
+            protocols: protocols,
+            parser: ResponseParser::new(),
+            accept: nonce.encode(),
+            error: res.err()
+        }
+    }
+
+    // Frame validation and size limits are configured on codec
+    #[inline] pub fn codec_mut(&mut self) -> &mut FrameCodec {
+        &mut self.codec
+    }
+
+    // Subprotocol selected by server, if any
+    #[inline] pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|v| &**v)
+    }
+
+    // Handshake response received from server
+    #[inline] pub fn response(&self) -> Option<&Response> {
+        self.response.as_ref()
+    }
+
+    #[inline] pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn read_message(&mut self) -> ReadMessage<S> {
+        ReadMessage { ws: self }
+    }
+
+    // Message is encoded on first poll, so dropping the future
+    // before that leaves nothing queued
+    pub fn send_message<'a>(&'a mut self, msg: &'a WSMessage) -> SendMessage<'a, S> {
+        SendMessage { ws: self, msg: Some(msg) }
+    }
+
+    pub fn poll_read_message(&mut self, cx: &mut Context) -> Poll<WSResult<WSMessage>> {
+        match try_poll!(self.poll_next_frame(cx)) {
+            Some(msg) => Poll::Ready(Ok(msg)),
+            None => Poll::Ready(Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed", None))))
+        }
+    }
+
+    // None if transport is closed between frames, or after read error
+    // (which is returned once, as connection can't be recovered from it)
+    fn poll_next_frame(&mut self, cx: &mut Context) -> Poll<WSResult<Option<WSMessage>>> {
+        if self.failed {
+            return Poll::Ready(Ok(None));
+        }
+
+        let res = self.poll_decode(cx);
+        if let Poll::Ready(Err(_)) = res {
+            self.failed = true;
+            self.read_buf.clear();
+        }
+        res
+    }
+
+    fn poll_decode(&mut self, cx: &mut Context) -> Poll<WSResult<Option<WSMessage>>> {
+        loop {
+            match self.codec.decode(&*self.read_buf) {
+                Ok(Some((msg, n))) => {
+                    self.read_buf.drain(..n);
+                    return Poll::Ready(Ok(Some(msg)));
+                },
+                Ok(None) => (),
+                Err(e) => return Poll::Ready(Err(e))
+            }
+
+            if try_poll!(self.poll_fill(cx)) == 0 {
+                return Poll::Ready(if self.read_buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(WSError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of frame", None)))
+                });
+            }
+        }
+    }
+
+    // Writes out all encoded frames and flushes transport
+    pub fn poll_flush(&mut self, cx: &mut Context) -> Poll<WSResult<()>> {
+        while !self.write_buf.is_empty() {
+            let n = try_poll!(Pin::new(&mut self.stream).poll_write(cx, &*self.write_buf));
+            if n == 0 {
+                return Poll::Ready(Err(WSError::Io(io::Error::new(io::ErrorKind::WriteZero, "failed to write frame", None))));
+            }
+            self.write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(try_poll!(Pin::new(&mut self.stream).poll_flush(cx))))
+    }
+
+    // Reads some more bytes into read buffer
+    fn poll_fill(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let mut chunk = [0u8; 4096];
+        let mut buf = ReadBuf::new(&mut chunk);
+        try_poll!(Pin::new(&mut self.stream).poll_read(cx, &mut buf));
+        self.read_buf.push_all(buf.filled());
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+pub struct Handshake<S> {
+    ws: Option<AsyncWebSocket<S>>,
+    protocols: Vec<String>,
+    parser: ResponseParser,
+    accept: Nonce,
+    error: Option<io::Error>
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Handshake<S> {
+    type Output = WSResult<AsyncWebSocket<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<WSResult<AsyncWebSocket<S>>> {
+        if let Some(e) = self.error.take() {
+            return Poll::Ready(Err(WSError::Io(e)));
+        }
+
+        let this = &mut *self;
+        let ws = match this.ws {
+            Some(ref mut ws) => ws,
+            None => return Poll::Ready(Err(WSError::NotConnected))
+        };
+
+        try_poll!(ws.poll_flush(cx));
+
+        loop {
+            // Bytes after response head belong to frames and are kept in read buffer
+            let parsed = match this.parser.parse(&*ws.read_buf) {
+                Ok(parsed) => parsed,
+                Err(e) => return Poll::Ready(Err(e))
+            };
+            if let Some((response, size)) = parsed {
+                ws.read_buf.drain(..size);
+
+                let response = match handshake::check_response(response, &*this.accept) {
+                    Ok(response) => response,
+                    Err(e) => return Poll::Ready(Err(e))
+                };
+                ws.protocol = match handshake::check_protocol(&response, &*this.protocols) {
+                    Ok(protocol) => protocol,
+                    Err(e) => return Poll::Ready(Err(e))
+                };
+                ws.response = Some(response);
+                break;
+            }
+
+            if try_poll!(ws.poll_fill(cx)) == 0 {
+                return Poll::Ready(Err(WSError::handshake("connection closed during handshake")));
+            }
+        }
+
+        Poll::Ready(Ok(this.ws.take().unwrap()))
+    }
+}
+
+pub struct Connect {
+    tcp: Option<Pin<Box<Future<Output = io::Result<TcpStream>> + Send>>>,
+    url: Option<Url>,
+    handshake: Option<Handshake<TcpStream>>
+}
+
+impl Future for Connect {
+    type Output = WSResult<AsyncWebSocket<TcpStream>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<WSResult<AsyncWebSocket<TcpStream>>> {
+        loop {
+            if let Some(ref mut handshake) = self.handshake {
+                return Pin::new(handshake).poll(cx);
+            }
+
+            let stream = match self.tcp {
+                Some(ref mut tcp) => try_poll!(tcp.as_mut().poll(cx)),
+                None => return Poll::Ready(Err(WSError::handshake("only ws:// URLs can be dialed, use from_stream() with TLS stream for wss://")))
+            };
+
+            let url = self.url.take().unwrap();
+            self.handshake = Some(AsyncWebSocket::from_stream(stream, url));
+        }
+    }
+}
+
+pub struct ReadMessage<'a, S: 'a> {
+    ws: &'a mut AsyncWebSocket<S>
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Future for ReadMessage<'a, S> {
+    type Output = WSResult<WSMessage>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<WSResult<WSMessage>> {
+        self.ws.poll_read_message(cx)
+    }
+}
+
+pub struct SendMessage<'a, S: 'a> {
+    ws: &'a mut AsyncWebSocket<S>,
+    msg: Option<&'a WSMessage>
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Future for SendMessage<'a, S> {
+    type Output = WSResult<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<WSResult<()>> {
+        let this = &mut *self;
+        if let Some(msg) = this.msg.take() {
+            if let Err(e) = this.ws.codec.encode(msg, &mut this.ws.write_buf) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        this.ws.poll_flush(cx)
+    }
+}
+
+// Incoming frames as a stream, ends when peer closes transport or after error
+#[cfg(feature = "futures")]
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for AsyncWebSocket<S> {
+    type Item = WSResult<WSMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<WSResult<WSMessage>>> {
+        match self.get_mut().poll_next_frame(cx) {
+            Poll::Ready(Ok(msg)) => Poll::Ready(msg.map(Ok)),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+// Outgoing frames, buffered until write buffer fills up or sink is flushed
+#[cfg(feature = "futures")]
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<WSMessage> for AsyncWebSocket<S> {
+    type Error = WSError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<WSResult<()>> {
+        let ws = self.get_mut();
+        if ws.write_buf.len() >= DEFAULT_FRAGMENT_SIZE {
+            AsyncWebSocket::poll_flush(ws, cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: WSMessage) -> WSResult<()> {
+        let ws = self.get_mut();
+        ws.codec.encode(&msg, &mut ws.write_buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<WSResult<()>> {
+        AsyncWebSocket::poll_flush(self.get_mut(), cx)
+    }
+
+    // Flushes pending frames and shuts transport down, close frame
+    // should be sent before that to close connection cleanly
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<WSResult<()>> {
+        let ws = self.get_mut();
+        try_poll!(AsyncWebSocket::poll_flush(ws, cx));
+        Poll::Ready(Ok(try_poll!(Pin::new(&mut ws.stream).poll_shutdown(cx))))
+    }
+}
+
+#[cfg(feature = "futures")]
+pub trait WSStreamExt: Stream<Item = WSResult<WSMessage>> + Sized {
+    // Joins fragments into whole messages, control frames in between are passed as is
+    fn defrag(self) -> Defrag<Self> {
+        Defrag { underlying: self, buffer: None, max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE), done: false }
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<St: Stream<Item = WSResult<WSMessage>>> WSStreamExt for St {}
+
+#[cfg(feature = "futures")]
+pub struct Defrag<St> {
+    underlying: St,
+    buffer: Option<WSMessage>,
+    max_message_size: Option<u64>,
+    // underlying stream has ended
+    done: bool
+}
+
+#[cfg(feature = "futures")]
+impl<St> Defrag<St> {
+    // Limit size of joined message (64 MiB by default), None to disable
+    pub fn max_message_size(mut self, limit: Option<u64>) -> Defrag<St> {
+        self.max_message_size = limit;
+        self
+    }
+
+    #[inline] pub fn into_inner(self) -> St {
+        self.underlying
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<St: Stream<Item = WSResult<WSMessage>> + Unpin> Stream for Defrag<St> {
+    type Item = WSResult<WSMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<WSResult<WSMessage>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            let msg = match Pin::new(&mut this.underlying).poll_next(cx) {
+                Poll::Ready(Some(Ok(msg))) => msg,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    this.done = true;
+                    // Fragments received so far are not a whole message
+                    return Poll::Ready(this.buffer.take().map(|_| Err(WSError::protocol(WSStatusCode::ProtocolError, "connection closed in the middle of fragmented message"))));
+                },
+                Poll::Pending => return Poll::Pending
+            };
+
+            if msg.is_whole() || msg.is_control() {
+                return Poll::Ready(Some(Ok(msg)));
+            } else if msg.is_first() {
+                if this.buffer.is_some() {
+                    this.buffer = None;
+                    return Poll::Ready(Some(Err(WSError::protocol(WSStatusCode::ProtocolError, "new message started before previous one finished"))));
+                }
+                this.buffer = Some(msg);
+            } else {
+                let mut buffer = match this.buffer.take() {
+                    Some(buffer) => buffer,
+                    None => return Poll::Ready(Some(Err(WSError::protocol(WSStatusCode::ProtocolError, "continuation frame without message start"))))
+                };
+
+                let size = (buffer.data.len() + msg.data.len()) as u64;
+                if let Some(limit) = this.max_message_size {
+                    if size > limit {
+                        return Poll::Ready(Some(Err(WSError::Capacity { limit: limit, size: size })));
+                    }
+                }
+
+                let last = msg.is_last();
+                buffer.push(msg);
+                if last {
+                    // Joined message keeps opcode of its first fragment
+                    buffer.header.insert(WS_FIN);
+                    return Poll::Ready(Some(Ok(buffer)));
+                }
+                this.buffer = Some(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "futures"))]
+mod tests {
+    use futures::executor::block_on;
+    use futures::stream::{self, StreamExt};
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use url::Url;
+    use message::WSMessage;
+    use error::WSError;
+    use codec::FrameCodec;
+    use super::{AsyncWebSocket, WSStreamExt};
+
+    // Client connected to in-memory peer, which sends given bytes and closes
+    fn client(input: &[u8]) -> AsyncWebSocket<DuplexStream> {
+        let (stream, mut peer) = duplex(1024);
+        block_on(peer.write_all(input)).unwrap();
+        drop(peer);
+        AsyncWebSocket {
+            stream: stream,
+            url: Url::parse("ws://localhost/").unwrap(),
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            codec: FrameCodec::new(false),
+            response: None,
+            protocol: None,
+            failed: false
+        }
+    }
+
+    #[test]
+    fn stream_ends_after_error() {
+        // RSV1 set without negotiated extension, followed by valid frame
+        let mut ws = client(b"\xc1\x00\x81\x02Hi");
+        match block_on(ws.next()) {
+            Some(Err(WSError::Protocol { .. })) => (),
+            _ => panic!("protocol error expected")
+        }
+        assert!(block_on(ws.next()).is_none());
+        assert!(block_on(ws.read_message()).is_err());
+    }
+
+    #[test]
+    fn defrag_incomplete_message() {
+        let frames = vec![Ok(WSMessage::text("Hello").first()), Ok(WSMessage::text(", ").more())];
+        let mut messages = stream::iter(frames).defrag();
+        match block_on(messages.next()) {
+            Some(Err(WSError::Protocol { .. })) => (),
+            _ => panic!("protocol error expected")
+        }
+        assert!(block_on(messages.next()).is_none());
+    }
+
+    #[test]
+    fn defrag_whole_message() {
+        let frames = vec![Ok(WSMessage::text("Hello").first()), Ok(WSMessage::text(", world!").last())];
+        let mut messages = stream::iter(frames).defrag();
+        let msg = block_on(messages.next()).unwrap().unwrap();
+        assert!(msg.is_text() && msg.is_whole());
+        assert_eq!(msg.data, b"Hello, world!".to_vec());
+        assert!(block_on(messages.next()).is_none());
+    }
+}
@@ -0,0 +1,420 @@
+use std::str;
+
+use message::{WSMessage, WSHeader, WSStatusCode, WS_FIN, WS_OPCODE, WS_OPCTRL, WS_OPCONT, WS_OPTEXT};
+use error::{WSError, WSResult};
+use extension::Extension;
+use validate::{self, Utf8Validator};
+use frame::{self, FrameHeader};
+use socket::{DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_MESSAGE_SIZE};
+
+// Protocol state of one side of connection, without any I/O: frames are parsed
+// incrementally from byte buffers and serialized into caller's buffer, so that it
+// can be driven by any event loop. Both blocking and async sockets are built on it.
+pub struct FrameCodec {
+    server: bool,
+    strict: bool,
+    extensions: Vec<Box<Extension + Send>>,
+    max_frame_size: Option<u64>,
+    max_message_size: Option<u64>,
+    // opcode of fragmented message being received (tracked in strict mode)
+    fragmented: Option<WSHeader>,
+    // opcode of the last data message started
+    opcode: WSHeader,
+    utf8: Utf8Validator,
+    // payload size of data message being received
+    message_size: u64,
+    // the same, including frame being streamed with extensions
+    streamed: u64
+}
+
+impl FrameCodec {
+    // `server` tells which side of connection codec is used on
+    pub fn new(server: bool) -> FrameCodec {
+        FrameCodec {
+            server: server,
+            strict: true,
+            extensions: Vec::new(),
+            max_frame_size: Some(DEFAULT_MAX_FRAME_SIZE),
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            fragmented: None,
+            opcode: WSHeader::empty(),
+            utf8: Utf8Validator::new(),
+            message_size: 0,
+            streamed: 0
+        }
+    }
+
+    #[inline] pub fn is_server(&self) -> bool {
+        self.server
+    }
+
+    // Negotiated extensions, applied to frames in order on encoding
+    // and in reverse order on decoding
+    pub fn set_extensions(&mut self, extensions: Vec<Box<Extension + Send>>) {
+        self.extensions = extensions;
+    }
+
+    #[inline] pub fn extensions(&self) -> &[Box<Extension + Send>] {
+        &*self.extensions
+    }
+
+    // Validate incoming frames strictly as per RFC 6455 (on by default)
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.strict = enabled;
+    }
+
+    // Limit frame payload size (16 MiB by default), None to disable
+    pub fn set_max_frame_size(&mut self, limit: Option<u64>) {
+        self.max_frame_size = limit;
+    }
+
+    // Limit size of whole (possibly fragmented) message payload (64 MiB by default),
+    // None to disable
+    pub fn set_max_message_size(&mut self, limit: Option<u64>) {
+        self.max_message_size = limit;
+    }
+
+    pub fn check_message_size(&self, size: u64) -> WSResult<()> {
+        match self.max_message_size {
+            Some(limit) if size > limit => Err(WSError::Capacity { limit: limit, size: size }),
+            _ => Ok(())
+        }
+    }
+
+    // Parses whole frame from the beginning of buffer, returning message and number
+    // of bytes consumed, or None if more bytes are needed (nothing is consumed then).
+    // Limits are checked as soon as header is available.
+    pub fn decode(&mut self, buf: &[u8]) -> WSResult<Option<(WSMessage, usize)>> {
+        let frame = match frame::decode_header(buf) {
+            Some(frame) => frame,
+            None => return Ok(None)
+        };
+        try!(self.check_size(&frame));
+
+        let end = frame.size as u64 + frame.len;
+        if (buf.len() as u64) < end {
+            return Ok(None);
+        }
+
+        try!(self.check_header(&frame));
+        let msg = try!(self.decode_payload(&frame, buf[frame.size..end as usize].to_vec()));
+        Ok(Some((msg, end as usize)))
+    }
+
+    // Parses and validates frame header only, for payload to be read separately
+    // with `decode_payload()` or streamed with `start_stream()`/`stream_payload()`
+    pub fn decode_header(&mut self, buf: &[u8]) -> WSResult<Option<FrameHeader>> {
+        let frame = match frame::decode_header(buf) {
+            Some(frame) => frame,
+            None => return Ok(None)
+        };
+        try!(self.check_size(&frame));
+        try!(self.check_header(&frame));
+        Ok(Some(frame))
+    }
+
+    // Builds message from frame payload: unmasks it, lets extensions transform it
+    // and validates the result
+    pub fn decode_payload(&mut self, frame: &FrameHeader, mut payload: Vec<u8>) -> WSResult<WSMessage> {
+        if let Some(mask) = frame.mask {
+            frame::apply_mask(&mut *payload, mask, 0);
+        }
+
+        // If this is the terminating frame (close command),
+        // first two bytes of data MUST BE u16 status code
+        let (mut msg, status) = frame::decode_payload(frame.header, payload);
+
+        if self.strict {
+            if let Some(code) = status {
+                try!(validate::check_close_code(code));
+            }
+        }
+
+        // Let negotiated extensions (e.g. permessage-deflate) transform message,
+        // within what's left of message size limit
+        let prior = if msg.is_cont() { self.message_size } else { 0 };
+        let budget = if msg.is_control() { None } else { self.max_message_size.map(|l| l.saturating_sub(prior)) };
+        for ext in self.extensions.iter_mut().rev() {
+            match ext.decode(&mut msg, budget) {
+                Err(WSError::Capacity { size, .. }) => return Err(WSError::Capacity { limit: self.max_message_size.unwrap_or(0), size: prior + size }),
+                res => try!(res)
+            }
+        }
+
+        // Extensions may inflate payload, so message size is accounted after them
+        if !msg.is_control() {
+            try!(self.account_message(msg.is_cont(), msg.is_final(), msg.data.len() as u64));
+        }
+
+        if self.strict {
+            if msg.is_close() {
+                if str::from_utf8(&*msg.data).is_err() {
+                    return Err(WSError::protocol(WSStatusCode::InvalidData, "invalid UTF-8 in close reason"));
+                }
+            } else if !msg.is_control() && self.opcode == WS_OPTEXT {
+                try!(self.utf8.feed(&*msg.data, msg.is_final()));
+            }
+        }
+
+        Ok(msg)
+    }
+
+    // Whether data frames can be streamed: no extensions are negotiated,
+    // or all of them transform payload chunk by chunk
+    pub fn can_stream(&self) -> bool {
+        self.extensions.iter().all(|e| e.streams())
+    }
+
+    // Starts streaming payload of data frame, with `stream_payload()` if no
+    // extensions are negotiated, or with `stream_decode()` otherwise
+    pub fn start_stream(&mut self, frame: &FrameHeader) -> WSResult<()> {
+        if !self.can_stream() {
+            return Err(WSError::protocol(WSStatusCode::ServerError, "frames transformed by extensions can't be streamed"));
+        }
+
+        let cont = frame.header & WS_OPCODE == WS_OPCONT;
+        if self.extensions.is_empty() {
+            self.account_message(cont, frame.header.contains(WS_FIN), frame.len)
+        } else {
+            // transformed payload is accounted as it's decoded
+            self.streamed = if cont { self.message_size } else { 0 };
+            Ok(())
+        }
+    }
+
+    // Unmasks and validates chunk of streamed payload in place,
+    // `offset` is position of chunk in frame payload
+    pub fn stream_payload(&mut self, frame: &FrameHeader, data: &mut [u8], offset: u64) -> WSResult<()> {
+        if let Some(mask) = frame.mask {
+            frame::apply_mask(data, mask, offset);
+        }
+
+        if self.strict && self.opcode == WS_OPTEXT {
+            let last = frame.header.contains(WS_FIN) && offset + data.len() as u64 == frame.len;
+            try!(self.utf8.feed(data, last));
+        }
+        Ok(())
+    }
+
+    // Unmasks chunk of streamed payload and lets extensions transform it,
+    // appending validated result to `out`. Chunk finishing frame must be passed
+    // even if it's empty.
+    pub fn stream_decode(&mut self, frame: &FrameHeader, data: &mut [u8], offset: u64, out: &mut Vec<u8>) -> WSResult<()> {
+        if let Some(mask) = frame.mask {
+            frame::apply_mask(data, mask, offset);
+        }
+
+        let last = offset + data.len() as u64 == frame.len;
+        let mut chunk = data.to_vec();
+        for ext in self.extensions.iter_mut().rev() {
+            let budget = self.max_message_size.map(|l| l.saturating_sub(self.streamed));
+            let mut next = Vec::new();
+            match ext.decode_chunk(frame.header, &*chunk, last, &mut next, budget) {
+                Err(WSError::Capacity { size, .. }) => return Err(WSError::Capacity { limit: self.max_message_size.unwrap_or(0), size: self.streamed + size }),
+                res => try!(res)
+            }
+            chunk = next;
+        }
+
+        self.streamed += chunk.len() as u64;
+        try!(self.check_message_size(self.streamed));
+
+        let fin = frame.header.contains(WS_FIN);
+        if self.strict && self.opcode == WS_OPTEXT {
+            try!(self.utf8.feed(&*chunk, last && fin));
+        }
+
+        if last {
+            self.message_size = if fin { 0 } else { self.streamed };
+        }
+        out.push_all(&*chunk);
+        Ok(())
+    }
+
+    // Serializes message into buffer after extensions transform it,
+    // masking is controlled by message's WS_MASK bit
+    pub fn encode(&mut self, msg: &WSMessage, out: &mut Vec<u8>) -> WSResult<()> {
+        let mut encoded: Option<WSMessage> = None;
+        for ext in self.extensions.iter_mut() {
+            if let Some(m) = try!(ext.encode(encoded.as_ref().unwrap_or(msg))) {
+                encoded = Some(m);
+            }
+        }
+
+        frame::encode_frame(encoded.as_ref().unwrap_or(msg), out);
+        Ok(())
+    }
+
+    // Checks limits before anything is allocated for payload
+    fn check_size(&self, frame: &FrameHeader) -> WSResult<()> {
+        if let Some(limit) = self.max_frame_size {
+            if frame.len > limit {
+                return Err(WSError::Capacity { limit: limit, size: frame.len });
+            }
+        }
+
+        if !frame.header.contains(WS_OPCTRL) {
+            let size = if frame.header & WS_OPCODE == WS_OPCONT { self.message_size } else { 0 } + frame.len;
+            try!(self.check_message_size(size));
+        }
+        Ok(())
+    }
+
+    fn check_header(&mut self, frame: &FrameHeader) -> WSResult<()> {
+        let header = frame.header;
+        if self.strict {
+            let rsv = self.extensions.iter().fold(WSHeader::empty(), |r, e| r | e.rsv());
+            let opcodes = self.extensions.iter().flat_map(|e| e.opcodes().into_iter()).collect::<Vec<WSHeader>>();
+            try!(validate::check_header(header, frame.len, self.server, rsv, &*opcodes));
+            self.fragmented = try!(validate::check_sequence(self.fragmented, header));
+        }
+
+        if !header.contains(WS_OPCTRL) && header & WS_OPCODE != WS_OPCONT {
+            self.opcode = header & WS_OPCODE;
+        }
+        Ok(())
+    }
+
+    fn account_message(&mut self, cont: bool, fin: bool, len: u64) -> WSResult<()> {
+        let size = if cont { self.message_size } else { 0 } + len;
+        try!(self.check_message_size(size));
+        self.message_size = if fin { 0 } else { size };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameCodec;
+    use message::{WSMessage, WS_OPBIN, WS_OPTEXT};
+    use error::WSError;
+
+    // Server frame with binary payload of `len` bytes and 16 or 64-bit length
+    fn binary_frame(len: usize) -> Vec<u8> {
+        let mut buf = vec![0x82];
+        if len < 126 {
+            buf.push(len as u8);
+        } else if len <= 0xffff {
+            buf.push(126);
+            buf.push((len >> 8) as u8);
+            buf.push(len as u8);
+        } else {
+            buf.push(127);
+            for i in (0..8).rev() {
+                buf.push((len as u64 >> (i * 8)) as u8);
+            }
+        }
+        buf.extend((0..len).map(|i| i as u8));
+        buf
+    }
+
+    #[test]
+    fn short_frame() {
+        let mut codec = FrameCodec::new(false);
+        let (msg, n) = codec.decode(b"\x81\x02Hi").unwrap().unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(msg.opcode(), WS_OPTEXT);
+        assert!(msg.is_final());
+        assert_eq!(msg.data, b"Hi".to_vec());
+    }
+
+    #[test]
+    fn header_split_across_reads() {
+        let buf = binary_frame(300);
+        let mut codec = FrameCodec::new(false);
+        // first byte, first two bytes without extended length, header without payload
+        for cut in [1, 2, 3, 4, 100].iter() {
+            assert!(codec.decode(&buf[..*cut]).unwrap().is_none(), "decoded from {} bytes", cut);
+        }
+        let (msg, n) = codec.decode(&*buf).unwrap().unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(msg.data.len(), 300);
+    }
+
+    #[test]
+    fn bytes_after_frame_are_not_consumed() {
+        let mut buf = binary_frame(5);
+        buf.push_all(b"\x81\x02Hi");
+        let mut codec = FrameCodec::new(false);
+        let (msg, n) = codec.decode(&*buf).unwrap().unwrap();
+        assert_eq!(n, 7);
+        assert_eq!(msg.data, vec![0, 1, 2, 3, 4]);
+        let (msg, n) = codec.decode(&buf[n..]).unwrap().unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(msg.data, b"Hi".to_vec());
+    }
+
+    #[test]
+    fn length_boundaries() {
+        for len in [0, 125, 126, 0xffff, 0x10000].iter() {
+            let buf = binary_frame(*len);
+            let mut codec = FrameCodec::new(false);
+            let (msg, n) = codec.decode(&*buf).unwrap().unwrap();
+            assert_eq!(n, buf.len());
+            assert_eq!(msg.opcode(), WS_OPBIN);
+            assert_eq!(msg.data.len(), *len);
+            assert_eq!(msg.data.last().map(|b| *b), if *len > 0 { Some((*len - 1) as u8) } else { None });
+        }
+    }
+
+    #[test]
+    fn encoded_length_boundaries() {
+        for len in [125, 126, 0xffff, 0x10000].iter() {
+            let data = (0..*len).map(|i| i as u8).collect::<Vec<u8>>();
+            let mut out = Vec::new();
+            FrameCodec::new(true).encode(&WSMessage::binary(&*data), &mut out).unwrap();
+            assert_eq!(out, binary_frame(*len));
+        }
+    }
+
+    #[test]
+    fn masked_frame_from_rfc() {
+        // RFC 6455 section 5.7, masked "Hello"
+        let buf = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+        let mut codec = FrameCodec::new(true);
+        let (msg, n) = codec.decode(buf).unwrap().unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(msg.data, b"Hello".to_vec());
+    }
+
+    #[test]
+    fn masked_round_trip() {
+        let data = (0..70000).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut out = Vec::new();
+        FrameCodec::new(false).encode(&WSMessage::binary(&*data).mask(), &mut out).unwrap();
+        assert_eq!(&out[..2], &[0x82, 0xff][..]);
+
+        let mut codec = FrameCodec::new(true);
+        let (msg, n) = codec.decode(&*out).unwrap().unwrap();
+        assert_eq!(n, out.len());
+        assert_eq!(msg.data, data);
+    }
+
+    #[test]
+    fn mask_direction_is_checked() {
+        assert!(FrameCodec::new(true).decode(b"\x81\x02Hi").is_err());
+        assert!(FrameCodec::new(false).decode(b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58").is_err());
+    }
+
+    #[test]
+    fn frame_size_limit_is_checked_on_header() {
+        let buf = binary_frame(300);
+        let mut codec = FrameCodec::new(false);
+        codec.set_max_frame_size(Some(200));
+        match codec.decode(&buf[..4]) {
+            Err(WSError::Capacity { limit, size }) => assert_eq!((limit, size), (200, 300)),
+            res => panic!("unexpected result: {:?}", res)
+        }
+    }
+
+    #[test]
+    fn message_size_limit_across_fragments() {
+        let mut codec = FrameCodec::new(false);
+        codec.set_max_message_size(Some(6));
+        assert!(codec.decode(b"\x02\x04abcd").unwrap().is_some());
+        match codec.decode(b"\x80\x04efgh") {
+            Err(WSError::Capacity { limit, size }) => assert_eq!((limit, size), (6, 8)),
+            res => panic!("unexpected result: {:?}", res)
+        }
+    }
+}
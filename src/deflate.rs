@@ -0,0 +1,586 @@
+use std::fmt;
+use std::usize;
+use std::str::FromStr;
+use flate2::{Compress, Decompress, Compression, FlushCompress, FlushDecompress, Status};
+
+use message::{WSMessage, WSHeader, WSStatusCode, WS_RSV1, WS_FIN, WS_OPCODE, WS_OPCONT, WS_OPCTRL};
+use error::{WSError, WSResult};
+use extension::Extension;
+
+pub static PERMESSAGE_DEFLATE: &'static str = "permessage-deflate";
+
+// Every sync flushed deflate block ends with these bytes,
+// they are stripped from the wire as per RFC 7692 section 7.2.1
+static DEFLATE_TAIL: &'static [u8] = &[0x00, 0x00, 0xff, 0xff];
+
+// permessage-deflate extension parameters (RFC 7692), either register it
+// with `ws.add_extension(Box::new(PerMessageDeflate::new(config)))`,
+// or put its string form into `extensions` list of `WebSocket::with_options()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeflateConfig {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: Option<u8>,
+    // None offers the parameter without value, letting server choose
+    pub client_max_window_bits: Option<u8>
+}
+
+impl DeflateConfig {
+    pub fn new() -> DeflateConfig {
+        DeflateConfig {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: None,
+            client_max_window_bits: None
+        }
+    }
+
+    pub fn server_no_context_takeover(mut self) -> DeflateConfig {
+        self.server_no_context_takeover = true;
+        self
+    }
+
+    pub fn client_no_context_takeover(mut self) -> DeflateConfig {
+        self.client_no_context_takeover = true;
+        self
+    }
+
+    pub fn server_max_window_bits(mut self, bits: u8) -> DeflateConfig {
+        self.server_max_window_bits = Some(if bits < 9 { 9 } else { bits });
+        self
+    }
+
+    // zlib can't compress with 256 byte window, so 9 bits is the least window
+    // offered or accepted for compressing side (RFC 7692 allows 8)
+    pub fn client_max_window_bits(mut self, bits: u8) -> DeflateConfig {
+        self.client_max_window_bits = Some(if bits < 9 { 9 } else { bits });
+        self
+    }
+
+    // Checks server's response parameters against this offer
+    // and creates client side compression context
+    pub fn accept(&self, response: &str) -> WSResult<Deflate> {
+        let mut params = response.split(';').map(|p| p.trim());
+        if params.next() != Some(PERMESSAGE_DEFLATE) {
+            return Err(WSError::handshake("not a permessage-deflate extension response"));
+        }
+
+        let mut server_no_context_takeover = false;
+        let mut client_no_context_takeover = self.client_no_context_takeover;
+        let mut client_max_window_bits = 15;
+        let mut server_max_window_bits = 15;
+        let mut seen = Vec::new();
+
+        for param in params {
+            let mut kv = param.splitn(2, '=').map(|s| s.trim().trim_matches('"'));
+            let (name, value) = (kv.next().unwrap_or(""), kv.next());
+
+            if seen.contains(&name) {
+                return Err(WSError::handshake("duplicate permessage-deflate parameter"));
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) => server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => client_no_context_takeover = true,
+                ("server_max_window_bits", Some(v)) => match parse_window_bits(v) {
+                    Some(bits) if self.server_max_window_bits.map(|m| bits <= m).unwrap_or(true) => server_max_window_bits = bits,
+                    _ => return Err(WSError::handshake("invalid server_max_window_bits in permessage-deflate response"))
+                },
+                ("client_max_window_bits", Some(v)) => match parse_window_bits(v) {
+                    Some(bits) if self.client_max_window_bits.map(|m| bits <= m).unwrap_or(true) => client_max_window_bits = bits,
+                    _ => return Err(WSError::handshake("invalid client_max_window_bits in permessage-deflate response"))
+                },
+                _ => return Err(WSError::handshake("unknown permessage-deflate parameter in response"))
+            }
+        }
+
+        if self.server_no_context_takeover && !server_no_context_takeover {
+            return Err(WSError::handshake("server_no_context_takeover was not accepted"));
+        }
+
+        if let Some(bits) = self.client_max_window_bits {
+            client_max_window_bits = if client_max_window_bits < bits { client_max_window_bits } else { bits };
+        }
+        if client_max_window_bits < 9 {
+            return Err(WSError::handshake("client_max_window_bits=8 is not supported"));
+        }
+
+        Ok(Deflate::new(client_max_window_bits, server_max_window_bits, client_no_context_takeover, server_no_context_takeover))
+    }
+
+    // Server side: builds response to client's offer, merging it with this config,
+    // and creates server side compression context
+    pub fn respond(&self, offer: &str) -> Option<(String, Deflate)> {
+        let client_window_bits_supported = offer.split(';').skip(1)
+            .any(|p| p.splitn(2, '=').next().unwrap_or("").trim() == "client_max_window_bits");
+        let offer = match offer.parse::<DeflateConfig>() {
+            Ok(offer) => offer,
+            Err(_) => return None
+        };
+
+        let mut response = DeflateConfig {
+            server_no_context_takeover: offer.server_no_context_takeover || self.server_no_context_takeover,
+            client_no_context_takeover: offer.client_no_context_takeover || self.client_no_context_takeover,
+            server_max_window_bits: match (offer.server_max_window_bits, self.server_max_window_bits) {
+                (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+                (a, b) => a.or(b)
+            },
+            client_max_window_bits: None
+        };
+
+        let mut value = PERMESSAGE_DEFLATE.to_string();
+        if response.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+        if response.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = response.server_max_window_bits {
+            value.push_str(&*format!("; server_max_window_bits={}", bits));
+        }
+        // Client window can be limited only if client announced support for it
+        if client_window_bits_supported {
+            response.client_max_window_bits = match (offer.client_max_window_bits, self.client_max_window_bits) {
+                (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+                (a, b) => a.or(b)
+            };
+            if let Some(bits) = response.client_max_window_bits {
+                value.push_str(&*format!("; client_max_window_bits={}", bits));
+            }
+        }
+
+        // Decline offer requiring window we can't compress with
+        if response.server_max_window_bits.map(|bits| bits < 9).unwrap_or(false) {
+            return None;
+        }
+
+        let deflate = Deflate::new(response.server_max_window_bits.unwrap_or(15), response.client_max_window_bits.unwrap_or(15),
+                                   response.server_no_context_takeover, response.client_no_context_takeover);
+        Some((value, deflate))
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    match value.parse::<u8>() {
+        Ok(bits) if 8 <= bits && bits <= 15 => Some(bits),
+        _ => None
+    }
+}
+
+impl fmt::Display for DeflateConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(f.write_str(PERMESSAGE_DEFLATE));
+        if self.server_no_context_takeover {
+            try!(f.write_str("; server_no_context_takeover"));
+        }
+        if self.client_no_context_takeover {
+            try!(f.write_str("; client_no_context_takeover"));
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            try!(write!(f, "; server_max_window_bits={}", bits));
+        }
+        match self.client_max_window_bits {
+            Some(bits) => write!(f, "; client_max_window_bits={}", bits),
+            None => f.write_str("; client_max_window_bits")
+        }
+    }
+}
+
+#[derive(Copy, Debug)]
+pub struct DeflateConfigParseError;
+
+impl FromStr for DeflateConfig {
+    type Err = DeflateConfigParseError;
+    fn from_str(s: &str) -> Result<DeflateConfig, DeflateConfigParseError> {
+        let mut params = s.split(';').map(|p| p.trim());
+        if params.next() != Some(PERMESSAGE_DEFLATE) {
+            return Err(DeflateConfigParseError);
+        }
+
+        let mut config = DeflateConfig::new();
+        for param in params {
+            let mut kv = param.splitn(2, '=').map(|s| s.trim().trim_matches('"'));
+            match (kv.next().unwrap_or(""), kv.next()) {
+                ("server_no_context_takeover", None) => config.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => config.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(v)) => config.server_max_window_bits = Some(try!(parse_window_bits(v).ok_or(DeflateConfigParseError))),
+                ("client_max_window_bits", Some(v)) => config.client_max_window_bits = Some(try!(parse_window_bits(v).ok_or(DeflateConfigParseError))),
+                ("client_max_window_bits", None) => config.client_max_window_bits = None,
+                _ => return Err(DeflateConfigParseError)
+            }
+        }
+        Ok(config)
+    }
+}
+
+// Negotiated compression context
+pub struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    compress_reset: bool,
+    decompress_reset: bool,
+    deflating: bool,
+    inflating: bool
+}
+
+impl Deflate {
+    // Window sizes are negotiated ones, peer may compress with 8 bit window,
+    // which is inflated fine with 9 bit one
+    pub fn new(compress_bits: u8, decompress_bits: u8, compress_reset: bool, decompress_reset: bool) -> Deflate {
+        Deflate {
+            compress: Compress::new_with_window_bits(Compression::default(), false, compress_bits),
+            decompress: Decompress::new_with_window_bits(false, if decompress_bits < 9 { 9 } else { decompress_bits }),
+            compress_reset: compress_reset,
+            decompress_reset: decompress_reset,
+            deflating: false,
+            inflating: false
+        }
+    }
+
+    // Compresses data messages, fragmented ones frame by frame with RSV1 set
+    // on the first frame only; control and empty whole messages are sent as is
+    pub fn encode(&mut self, msg: &WSMessage) -> WSResult<Option<WSMessage>> {
+        if msg.is_control() || (msg.is_whole() && msg.data.is_empty()) {
+            return Ok(None);
+        }
+
+        if !msg.is_cont() {
+            self.deflating = true;
+        } else if !self.deflating {
+            return Ok(None);
+        }
+
+        let mut data = Vec::with_capacity(msg.data.len() / 2 + 16);
+        let start = self.compress.total_in();
+        loop {
+            let pos = (self.compress.total_in() - start) as usize;
+            if data.len() == data.capacity() {
+                data.reserve(msg.data.len() / 4 + 64);
+            }
+            try!(self.compress.compress_vec(&msg.data[pos..], &mut data, FlushCompress::Sync)
+                 .map_err(|_| WSError::protocol(WSStatusCode::ServerError, "deflate compression error")));
+
+            // Sync flush is complete when all input is consumed and output buffer is not full
+            if (self.compress.total_in() - start) as usize == msg.data.len() && data.len() < data.capacity() {
+                break;
+            }
+        }
+
+        // Every fragment ends with tail, but it's stripped from the end of message only
+        if msg.is_final() {
+            if data.ends_with(DEFLATE_TAIL) {
+                let len = data.len() - DEFLATE_TAIL.len();
+                data.truncate(len);
+            }
+
+            self.deflating = false;
+            if self.compress_reset {
+                self.compress.reset();
+            }
+        }
+
+        let header = if msg.is_cont() { msg.header } else { msg.header | WS_RSV1 };
+        Ok(Some(WSMessage { header: header, data: data, status: msg.status }))
+    }
+
+    // Decompresses data frames of messages with RSV1 bit set on first frame,
+    // failing as soon as output exceeds `limit`
+    pub fn decode(&mut self, msg: &mut WSMessage, limit: Option<u64>) -> WSResult<()> {
+        try!(check_rsv1(msg.header));
+        if msg.is_control() {
+            return Ok(());
+        }
+
+        if !msg.is_cont() {
+            self.inflating = msg.header.contains(WS_RSV1);
+        }
+
+        if !self.inflating {
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        try!(self.inflate(&*msg.data, msg.is_final(), msg.data.len() * 4, &mut data, limit));
+
+        msg.header.remove(WS_RSV1);
+        msg.data = data;
+        Ok(())
+    }
+
+    // Same as `decode()` for chunk of frame payload, `last` is set for the chunk finishing frame
+    pub fn decode_chunk(&mut self, header: WSHeader, chunk: &[u8], last: bool, out: &mut Vec<u8>, limit: Option<u64>) -> WSResult<()> {
+        try!(check_rsv1(header));
+        if header.contains(WS_OPCTRL) {
+            out.push_all(chunk);
+            return Ok(());
+        }
+
+        if header & WS_OPCODE != WS_OPCONT {
+            self.inflating = header.contains(WS_RSV1);
+        }
+
+        if !self.inflating {
+            out.push_all(chunk);
+            return Ok(());
+        }
+
+        self.inflate(chunk, last && header.contains(WS_FIN), chunk.len() * 4, out, limit)
+    }
+
+    // Inflates `input` appending to `out`, `end` is set for the end of message.
+    // Output never grows past one byte over limit, which is enough to tell it's exceeded.
+    fn inflate(&mut self, data: &[u8], end: bool, hint: usize, out: &mut Vec<u8>, limit: Option<u64>) -> WSResult<()> {
+        let mut input = Vec::with_capacity(data.len() + DEFLATE_TAIL.len());
+        input.push_all(data);
+        if end {
+            input.push_all(DEFLATE_TAIL);
+        }
+
+        let base = out.len();
+        let max = limit.map(|l| if l < (usize::MAX as u64) { l as usize + 1 } else { usize::MAX });
+        let bounded = |want: usize, len: usize| max.map(|m| if want < m - len { want } else { m - len }).unwrap_or(want);
+
+        if out.capacity() == out.len() {
+            out.reserve_exact(bounded(hint + 64, 0));
+        }
+        let start = self.decompress.total_in();
+        loop {
+            let pos = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                let len = out.len() - base;
+                out.reserve_exact(bounded(input.len() * 2 + 64, len));
+            }
+            let status = try!(self.decompress.decompress_vec(&input[pos..], out, FlushDecompress::Sync)
+                              .map_err(|_| WSError::protocol(WSStatusCode::InvalidData, "invalid deflate data")));
+
+            if let Some(limit) = limit {
+                if (out.len() - base) as u64 > limit {
+                    return Err(WSError::Capacity { limit: limit, size: (out.len() - base) as u64 });
+                }
+            }
+
+            if ((self.decompress.total_in() - start) as usize == input.len() && out.len() < out.capacity()) || status == Status::StreamEnd {
+                break;
+            }
+        }
+
+        if end {
+            self.inflating = false;
+            if self.decompress_reset {
+                self.decompress.reset(false);
+            }
+        }
+        Ok(())
+    }
+}
+
+// RSV1 marks compressed message on its first frame only (RFC 7692 section 6)
+fn check_rsv1(header: WSHeader) -> WSResult<()> {
+    if header.contains(WS_RSV1) && (header.contains(WS_OPCTRL) || header & WS_OPCODE == WS_OPCONT) {
+        return Err(WSError::protocol(WSStatusCode::ProtocolError, "RSV1 set on continuation or control frame"));
+    }
+    Ok(())
+}
+
+// permessage-deflate as a pluggable extension, owns RSV1 bit
+pub struct PerMessageDeflate {
+    config: DeflateConfig,
+    context: Option<Deflate>
+}
+
+impl PerMessageDeflate {
+    pub fn new(config: DeflateConfig) -> PerMessageDeflate {
+        PerMessageDeflate {
+            config: config,
+            context: None
+        }
+    }
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str {
+        PERMESSAGE_DEFLATE
+    }
+
+    fn offer(&self) -> String {
+        self.config.to_string()
+    }
+
+    fn accept(&mut self, response: &str) -> WSResult<()> {
+        self.context = Some(try!(self.config.accept(response)));
+        Ok(())
+    }
+
+    fn respond(&mut self, offer: &str) -> Option<String> {
+        self.config.respond(offer).map(|(value, context)| {
+            self.context = Some(context);
+            value
+        })
+    }
+
+    fn rsv(&self) -> WSHeader {
+        WS_RSV1
+    }
+
+    fn encode(&mut self, msg: &WSMessage) -> WSResult<Option<WSMessage>> {
+        match self.context {
+            Some(ref mut deflate) => deflate.encode(msg),
+            None => Ok(None)
+        }
+    }
+
+    fn decode(&mut self, msg: &mut WSMessage, limit: Option<u64>) -> WSResult<()> {
+        match self.context {
+            Some(ref mut deflate) => deflate.decode(msg, limit),
+            None => Ok(())
+        }
+    }
+
+    fn streams(&self) -> bool {
+        true
+    }
+
+    fn decode_chunk(&mut self, header: WSHeader, chunk: &[u8], last: bool, out: &mut Vec<u8>, limit: Option<u64>) -> WSResult<()> {
+        match self.context {
+            Some(ref mut deflate) => deflate.decode_chunk(header, chunk, last, out, limit),
+            None => {
+                out.push_all(chunk);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use message::{WSMessage, WS_RSV1, WS_FIN, WS_OPTEXT};
+    use error::WSError;
+    use super::{DeflateConfig, Deflate, DEFLATE_TAIL};
+
+    fn pair() -> (Deflate, Deflate) {
+        (Deflate::new(15, 15, false, false), Deflate::new(15, 15, false, false))
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut tx, mut rx) = pair();
+        for text in ["Hello, hello, hello, hello!", "Hello again", "x"].iter() {
+            let mut msg = tx.encode(&WSMessage::text(*text)).unwrap().unwrap();
+            assert!(msg.header.contains(WS_RSV1 | WS_FIN));
+            rx.decode(&mut msg, None).unwrap();
+            assert!(!msg.header.contains(WS_RSV1));
+            assert_eq!(msg.data, text.as_bytes().to_vec());
+        }
+    }
+
+    #[test]
+    fn tail_is_stripped() {
+        let (mut tx, _) = pair();
+        let msg = tx.encode(&WSMessage::text("Hello")).unwrap().unwrap();
+        assert!(!msg.data.ends_with(DEFLATE_TAIL));
+
+        // RFC 7692 section 7.2.3.1
+        let mut msg = WSMessage { header: WS_FIN | WS_RSV1 | WS_OPTEXT, data: vec![0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], status: None };
+        Deflate::new(15, 15, false, false).decode(&mut msg, None).unwrap();
+        assert_eq!(msg.data, b"Hello".to_vec());
+    }
+
+    #[test]
+    fn empty_and_control_messages_are_not_compressed() {
+        let (mut tx, _) = pair();
+        assert!(tx.encode(&WSMessage::text("")).unwrap().is_none());
+        assert!(tx.encode(&WSMessage::ping(b"ping")).unwrap().is_none());
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let (mut tx, mut rx) = pair();
+        let parts = [WSMessage::text("Hello, ").first(), WSMessage::text("fragmented ").more(), WSMessage::text("world").last()];
+
+        let mut data = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let mut frame = tx.encode(part).unwrap().unwrap();
+            assert_eq!(frame.header.contains(WS_RSV1), i == 0);
+            assert_eq!(frame.header.contains(WS_FIN), i == 2);
+            rx.decode(&mut frame, None).unwrap();
+            data.push_all(&*frame.data);
+        }
+        assert_eq!(data, b"Hello, fragmented world".to_vec());
+    }
+
+    #[test]
+    fn rsv1_on_continuation_frame() {
+        let mut msg = WSMessage::text("x").last();
+        msg.header.insert(WS_RSV1);
+        match Deflate::new(15, 15, false, false).decode(&mut msg, None) {
+            Err(WSError::Protocol { .. }) => (),
+            res => panic!("unexpected result: {:?}", res)
+        }
+    }
+
+    #[test]
+    fn inflate_limit() {
+        let (mut tx, mut rx) = pair();
+        let data = vec![0u8; 100000];
+        let mut msg = tx.encode(&WSMessage::binary(&*data)).unwrap().unwrap();
+        assert!(msg.data.len() < 1000);
+        match rx.decode(&mut msg, Some(1000)) {
+            Err(WSError::Capacity { limit, size }) => assert!(limit == 1000 && size > 1000 && size <= 1001),
+            res => panic!("unexpected result: {:?}", res)
+        }
+    }
+
+    #[test]
+    fn accept_response() {
+        let config = DeflateConfig::new().client_max_window_bits(12);
+        assert!(config.accept("permessage-deflate").is_ok());
+        assert!(config.accept("permessage-deflate; server_no_context_takeover; client_max_window_bits=10").is_ok());
+        assert!(config.accept("permessage-deflate; client_max_window_bits=13").is_err());
+        assert!(config.accept("permessage-deflate; client_max_window_bits=8").is_err());
+        assert!(config.accept("permessage-deflate; server_max_window_bits=16").is_err());
+        assert!(config.accept("permessage-deflate; server_no_context_takeover; server_no_context_takeover").is_err());
+        assert!(config.accept("permessage-deflate; unknown").is_err());
+        assert!(config.accept("x-webkit-deflate-frame").is_err());
+
+        let config = DeflateConfig::new().server_no_context_takeover().server_max_window_bits(10);
+        assert!(config.accept("permessage-deflate").is_err());
+        assert!(config.accept("permessage-deflate; server_no_context_takeover; server_max_window_bits=11").is_err());
+        assert!(config.accept("permessage-deflate; server_no_context_takeover; server_max_window_bits=10").is_ok());
+    }
+
+    #[test]
+    fn respond_to_offer() {
+        let respond = |config: DeflateConfig, offer: &str| config.respond(offer).map(|(value, _)| value);
+
+        assert_eq!(respond(DeflateConfig::new(), "permessage-deflate"), Some("permessage-deflate".to_string()));
+        assert_eq!(respond(DeflateConfig::new(), "permessage-deflate; client_max_window_bits"), Some("permessage-deflate".to_string()));
+        assert_eq!(respond(DeflateConfig::new().client_max_window_bits(10), "permessage-deflate; client_max_window_bits"),
+                   Some("permessage-deflate; client_max_window_bits=10".to_string()));
+        // client window can't be limited unless client supports it
+        assert_eq!(respond(DeflateConfig::new().client_max_window_bits(10), "permessage-deflate"), Some("permessage-deflate".to_string()));
+        assert_eq!(respond(DeflateConfig::new(), "permessage-deflate; server_no_context_takeover; server_max_window_bits=10"),
+                   Some("permessage-deflate; server_no_context_takeover; server_max_window_bits=10".to_string()));
+        assert_eq!(respond(DeflateConfig::new().server_max_window_bits(11), "permessage-deflate; server_max_window_bits=12"),
+                   Some("permessage-deflate; server_max_window_bits=11".to_string()));
+        assert_eq!(respond(DeflateConfig::new().client_no_context_takeover(), "permessage-deflate"),
+                   Some("permessage-deflate; client_no_context_takeover".to_string()));
+
+        assert_eq!(respond(DeflateConfig::new(), "permessage-deflate; server_max_window_bits=8"), None);
+        assert_eq!(respond(DeflateConfig::new(), "permessage-deflate; unknown"), None);
+    }
+
+    #[test]
+    fn no_context_takeover() {
+        let mut tx = Deflate::new(15, 15, true, false);
+        let mut rx = Deflate::new(15, 15, false, true);
+        for _ in 0..3 {
+            let mut msg = tx.encode(&WSMessage::text("repeated text, repeated text")).unwrap().unwrap();
+            // each message is compressed from scratch, so it can be inflated by fresh context
+            let mut copy = WSMessage { header: msg.header, data: msg.data.clone(), status: None };
+            Deflate::new(15, 15, false, false).decode(&mut copy, None).unwrap();
+            rx.decode(&mut msg, None).unwrap();
+            assert_eq!(msg.data, copy.data);
+        }
+    }
+}
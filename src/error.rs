@@ -0,0 +1,138 @@
+use std::io;
+use std::fmt;
+use std::error::{Error, FromError};
+use std::collections::BTreeMap;
+
+use message::WSStatusCode;
+
+#[derive(Debug)]
+pub enum WSError {
+    // Underlying transport failure
+    Io(io::Error),
+    // Client is not connected yet
+    NotConnected,
+    // Closing handshake is started or completed
+    Closed,
+    // Opening handshake failed, with HTTP status code and headers of response
+    // (or request on server side) if they were received
+    Handshake {
+        status: Option<u16>,
+        headers: BTreeMap<String, String>,
+        reason: &'static str
+    },
+    // Peer violated protocol, status code should be sent back in close frame
+    Protocol {
+        status: WSStatusCode,
+        reason: &'static str
+    },
+    // Frame or message exceeds configured limit
+    Capacity {
+        limit: u64,
+        size: u64
+    },
+    // TLS layer failure
+    Tls(Box<Error + Send>),
+    // Client certificate couldn't be loaded or server rejected it
+    ClientCertificate {
+        reason: &'static str,
+        cause: Option<Box<Error + Send>>
+    }
+}
+
+pub type WSResult<T> = Result<T, WSError>;
+
+impl WSError {
+    #[inline] pub fn handshake(reason: &'static str) -> WSError {
+        WSError::Handshake { status: None, headers: BTreeMap::new(), reason: reason }
+    }
+
+    #[inline] pub fn client_certificate<E: Error + Send + 'static>(reason: &'static str, cause: E) -> WSError {
+        WSError::ClientCertificate { reason: reason, cause: Some(Box::new(cause)) }
+    }
+
+    #[inline] pub fn protocol(status: WSStatusCode, reason: &'static str) -> WSError {
+        WSError::Protocol { status: status, reason: reason }
+    }
+
+    // Status code to send to peer in close frame, if any
+    pub fn status(&self) -> Option<WSStatusCode> {
+        match *self {
+            WSError::Protocol { status, .. } => Some(status),
+            WSError::Capacity { .. } => Some(WSStatusCode::TooLargeData),
+            _ => None
+        }
+    }
+
+    // Whether reconnecting may help: transport failures and server side
+    // handshake failures (5xx, 429) are considered transient
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            WSError::Io(ref e) => match e.kind() {
+                io::ErrorKind::InvalidInput | io::ErrorKind::PermissionDenied => false,
+                _ => true
+            },
+            WSError::Handshake { status: Some(code), .. } => code >= 500 || code == 429,
+            WSError::Handshake { status: None, .. } => false,
+            WSError::Closed => true,
+            WSError::NotConnected | WSError::Protocol { .. } | WSError::Capacity { .. } | WSError::Tls(_) | WSError::ClientCertificate { .. } => false
+        }
+    }
+}
+
+impl fmt::Display for WSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WSError::Io(ref e) => write!(f, "I/O error: {}", e),
+            WSError::NotConnected => f.write_str("client not connected"),
+            WSError::Closed => f.write_str("connection closed"),
+            WSError::Handshake { status: Some(code), reason, .. } => write!(f, "handshake failed with status {}: {}", code, reason),
+            WSError::Handshake { status: None, reason, .. } => write!(f, "handshake failed: {}", reason),
+            WSError::Protocol { status, reason } => write!(f, "protocol error ({:?}): {}", status, reason),
+            WSError::Capacity { limit, size } => write!(f, "size {} exceeds limit {}", size, limit),
+            WSError::Tls(ref e) => write!(f, "TLS error: {}", e),
+            WSError::ClientCertificate { reason, cause: Some(ref e) } => write!(f, "client certificate error: {}: {}", reason, e),
+            WSError::ClientCertificate { reason, cause: None } => write!(f, "client certificate error: {}", reason)
+        }
+    }
+}
+
+impl Error for WSError {
+    fn description(&self) -> &str {
+        match *self {
+            WSError::Io(ref e) => e.description(),
+            WSError::NotConnected => "client not connected",
+            WSError::Closed => "connection closed",
+            WSError::Handshake { reason, .. } => reason,
+            WSError::Protocol { reason, .. } => reason,
+            WSError::Capacity { .. } => "size limit exceeded",
+            WSError::Tls(ref e) => e.description(),
+            WSError::ClientCertificate { reason, .. } => reason
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            WSError::Io(ref e) => Some(e as &Error),
+            WSError::Tls(ref e) => Some(&**e as &Error),
+            WSError::ClientCertificate { cause: Some(ref e), .. } => Some(&**e as &Error),
+            _ => None
+        }
+    }
+}
+
+impl FromError<io::Error> for WSError {
+    fn from_error(err: io::Error) -> WSError {
+        WSError::Io(err)
+    }
+}
+
+// For Read/Write adapters which must return io::Error
+impl FromError<WSError> for io::Error {
+    fn from_error(err: WSError) -> io::Error {
+        match err {
+            WSError::Io(e) => e,
+            WSError::NotConnected => io::Error::new(io::ErrorKind::NotConnected, "client not connected", None),
+            e => io::Error::new(io::ErrorKind::Other, "websocket error", Some(e.to_string()))
+        }
+    }
+}
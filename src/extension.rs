@@ -0,0 +1,86 @@
+use message::{WSMessage, WSHeader};
+use error::{WSError, WSResult};
+
+// Protocol extension participating in handshake negotiation and frames processing.
+//
+// Extension owns some RSV bits and (optionally) reserved opcodes, outgoing messages
+// are passed through `encode()` of active extensions in the order they are listed
+// in handshake response's Sec-WebSocket-Extensions header (server lists them in
+// client's order of preference), incoming messages are passed through `decode()`
+// in reverse order.
+pub trait Extension {
+    // Extension token as in Sec-WebSocket-Extensions header, e.g. "permessage-deflate"
+    fn name(&self) -> &str;
+
+    // Client side: full offer value for Sec-WebSocket-Extensions header
+    fn offer(&self) -> String {
+        self.name().to_string()
+    }
+
+    // Client side: validate server's response (full extension value with parameters)
+    // and activate extension, error fails the handshake
+    fn accept(&mut self, response: &str) -> WSResult<()>;
+
+    // Server side: reply to client's offer with response value, or decline it with None
+    fn respond(&mut self, _offer: &str) -> Option<String> {
+        None
+    }
+
+    // RSV bits used by extension, masked with WS_RSV
+    fn rsv(&self) -> WSHeader {
+        WSHeader::empty()
+    }
+
+    // Reserved opcodes used by extension, masked with WS_OPCODE
+    fn opcodes(&self) -> Vec<WSHeader> {
+        Vec::new()
+    }
+
+    // Transform outgoing message, None leaves message as is
+    fn encode(&mut self, _msg: &WSMessage) -> WSResult<Option<WSMessage>> {
+        Ok(None)
+    }
+
+    // Transform incoming message in place. `limit` is how large its payload may
+    // become (the rest of message size budget), extensions expanding payload
+    // must fail with WSError::Capacity as soon as it's exceeded.
+    fn decode(&mut self, _msg: &mut WSMessage, _limit: Option<u64>) -> WSResult<()> {
+        Ok(())
+    }
+
+    // Whether `decode_chunk()` is implemented, so that data frames can be
+    // streamed without being buffered whole
+    fn streams(&self) -> bool {
+        false
+    }
+
+    // Streaming counterpart of `decode()`: transforms chunk of data frame payload,
+    // appending result to `out`. `header` is frame's header, `last` is set for
+    // the chunk finishing the frame, `limit` is as for `decode()`.
+    fn decode_chunk(&mut self, _header: WSHeader, chunk: &[u8], _last: bool, out: &mut Vec<u8>, _limit: Option<u64>) -> WSResult<()> {
+        out.push_all(chunk);
+        Ok(())
+    }
+}
+
+// Extension token of offer or response header value
+pub fn extension_name(value: &str) -> &str {
+    value.split(';').next().unwrap_or("").trim()
+}
+
+// Splits Sec-WebSocket-Extensions header value into separate extensions
+pub fn split_extensions(value: &str) -> Vec<&str> {
+    value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()).collect()
+}
+
+// Checks extensions do not claim the same RSV bits or opcodes
+pub fn check_conflicts(exts: &[Box<Extension + Send>]) -> WSResult<()> {
+    for (i, a) in exts.iter().enumerate() {
+        for b in exts[i + 1..].iter() {
+            if a.rsv().intersects(b.rsv()) || a.opcodes().iter().any(|op| b.opcodes().contains(op)) {
+                return Err(WSError::handshake("extensions claim the same RSV bits or opcodes"));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::u16;
+use std::num::{FromPrimitive, ToPrimitive};
+use rand::{thread_rng, Rng};
+
+use message::{WSMessage, WSHeader, WS_MASK, WS_LEN, WS_LEN16, WS_LEN64, WS_OPCODE, WS_OPTERM};
+
+// Frame encoding and decoding primitives FrameCodec is built on,
+// they don't do any I/O and work on byte buffers only.
+
+// Frame header up to payload
+#[derive(Copy, Debug)]
+pub struct FrameHeader {
+    pub header: WSHeader,
+    pub len: u64,
+    pub mask: Option<u32>,
+    // size of encoded header on the wire
+    pub size: usize
+}
+
+// Size of encoded header, known from its first two bytes
+pub fn header_size(first: &[u8; 2]) -> usize {
+    let header = WSHeader::from_bits_truncate(((first[0] as u16) << 8) | first[1] as u16);
+    let wslen = header & WS_LEN;
+    2 + if wslen == WS_LEN16 { 2 } else if wslen == WS_LEN64 { 8 } else { 0 }
+      + if header.contains(WS_MASK) { 4 } else { 0 }
+}
+
+// Parses frame header from the beginning of buffer, None if more bytes are needed
+pub fn decode_header(buf: &[u8]) -> Option<FrameHeader> {
+    if buf.len() < 2 {
+        return None;
+    }
+
+    let size = header_size(&[buf[0], buf[1]]);
+    if buf.len() < size {
+        return None;
+    }
+
+    let header = WSHeader::from_bits_truncate(((buf[0] as u16) << 8) | buf[1] as u16);
+    let wslen = header & WS_LEN;
+    let (len, pos) = if wslen == WS_LEN16 {
+        (read_be(&buf[2..4]), 4)
+    } else if wslen == WS_LEN64 {
+        (read_be(&buf[2..10]), 10)
+    } else {
+        (wslen.bits() as u64, 2)
+    };
+
+    let mask = if header.contains(WS_MASK) {
+        Some(read_be(&buf[pos..pos + 4]) as u32)
+    } else {
+        None
+    };
+
+    Some(FrameHeader { header: header - WS_LEN, len: len, mask: mask, size: size })
+}
+
+// Builds message from unmasked payload, splitting status code off close frame
+pub fn decode_payload(header: WSHeader, mut payload: Vec<u8>) -> (WSMessage, Option<u16>) {
+    let status = if header & WS_OPCODE == WS_OPTERM && payload.len() >= 2 {
+        let code = read_be(&payload[..2]) as u16;
+        payload = payload[2..].to_vec();
+        Some(code)
+    } else {
+        None
+    };
+
+    (WSMessage { header: header, data: payload, status: status.and_then(FromPrimitive::from_u16) }, status)
+}
+
+// Serializes message into buffer, masking it with random key if WS_MASK is set
+pub fn encode_frame(msg: &WSMessage, out: &mut Vec<u8>) {
+    let status = msg.status.and_then(|s| s.to_u16());
+    let len = msg.data.len() as u64 + if status.is_some() { 2 } else { 0 };
+    let hdr = msg.header - WS_LEN;
+
+    // Encode length along with header
+    if len < WS_LEN16.bits() as u64 {
+        write_be(out, (hdr | WSHeader::from_bits_truncate(len as u16)).bits() as u64, 2);
+    } else if len <= u16::MAX as u64 {
+        write_be(out, (hdr | WS_LEN16).bits() as u64, 2);
+        write_be(out, len, 2);
+    } else {
+        write_be(out, (hdr | WS_LEN64).bits() as u64, 2);
+        write_be(out, len, 8);
+    }
+
+    let mask = if hdr.contains(WS_MASK) {
+        let mask = thread_rng().gen::<u32>();
+        write_be(out, mask as u64, 4);
+        Some(mask)
+    } else {
+        None
+    };
+
+    let start = out.len();
+    if let Some(code) = status {
+        write_be(out, code as u64, 2);
+    }
+    out.push_all(&*msg.data);
+
+    if let Some(mask) = mask {
+        apply_mask(&mut out[start..], mask, 0);
+    }
+}
+
+// Masks (or unmasks) data in place, `offset` is position of data in frame payload
+pub fn apply_mask(data: &mut [u8], mask: u32, offset: u64) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= (mask >> (24 - (((offset as usize + i) % 4) << 3)) & 0xff) as u8;
+    }
+}
+
+fn read_be(buf: &[u8]) -> u64 {
+    buf.iter().fold(0u64, |v, b| (v << 8) | *b as u64)
+}
+
+fn write_be(out: &mut Vec<u8>, value: u64, size: usize) {
+    for i in (0..size).rev() {
+        out.push((value >> (i << 3)) as u8);
+    }
+}
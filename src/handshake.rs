@@ -0,0 +1,550 @@
+use std::io::{Write, self};
+use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+use std::slice::SliceConcatExt;
+use url::{Url, UrlParser};
+
+use error::{WSError, WSResult};
+use extension::split_extensions;
+
+// Opening handshake pieces shared by blocking and async clients
+
+// Headers which are managed by handshake itself and can't be set by user
+static RESERVED_HEADERS: &'static [&'static str] = &["upgrade", "connection", "sec-websocket-key",
+    "sec-websocket-version", "sec-websocket-protocol", "sec-websocket-extensions"];
+
+// Headers carrying credentials, not sent to other hosts on redirect
+static CREDENTIAL_HEADERS: &'static [&'static str] = &["authorization", "proxy-authorization", "cookie"];
+
+// Extra headers of handshake request, e.g. for authentication:
+//
+//     RequestHeaders::new().header("Authorization", "Bearer token").no_origin()
+//
+// Host and Origin are derived from URL unless set explicitly.
+// Invalid header names and values with line breaks make
+// `write_request()` fail before anything is written.
+#[derive(Clone, Debug)]
+pub struct RequestHeaders {
+    host: Option<String>,
+    // None to derive from URL, Some(None) to omit
+    origin: Option<Option<String>>,
+    headers: Vec<(String, String)>,
+    // first invalid header added
+    error: Option<&'static str>
+}
+
+impl RequestHeaders {
+    pub fn new() -> RequestHeaders {
+        RequestHeaders { host: None, origin: None, headers: Vec::new(), error: None }
+    }
+
+    // Adds header, it can be added several times for multiple values.
+    // Host and Origin set this way replace default ones, handshake's own
+    // headers (Upgrade, Sec-WebSocket-*, ...) are ignored.
+    pub fn header(mut self, name: &str, value: &str) -> RequestHeaders {
+        if !is_token(name) {
+            self.error = self.error.or(Some("invalid request header name"));
+            return self;
+        }
+        if value.contains('\r') || value.contains('\n') {
+            self.error = self.error.or(Some("line break in request header value"));
+            return self;
+        }
+
+        match &*name.to_ascii_lowercase() {
+            "host" => self.host = Some(value.to_string()),
+            "origin" => self.origin = Some(Some(value.to_string())),
+            lower if RESERVED_HEADERS.iter().any(|h| *h == lower) => (),
+            _ => self.headers.push((name.to_string(), value.to_string()))
+        }
+        self
+    }
+
+    pub fn host(self, host: &str) -> RequestHeaders {
+        self.header("Host", host)
+    }
+
+    pub fn origin(self, origin: &str) -> RequestHeaders {
+        self.header("Origin", origin)
+    }
+
+    // Don't send Origin header at all
+    pub fn no_origin(mut self) -> RequestHeaders {
+        self.origin = Some(None);
+        self
+    }
+
+    // Headers to send after redirect to another host: Host override
+    // and credentials (Authorization, Proxy-Authorization, Cookie) are dropped
+    pub fn for_other_host(&self) -> RequestHeaders {
+        let mut request = self.clone();
+        request.host = None;
+        request.headers.retain(|&(ref name, _)| !CREDENTIAL_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)));
+        request
+    }
+}
+
+pub fn write_request<W: Write>(s: &mut W, url: &Url, nonce: &str, version: u32, protocols: &[String], extensions: &[String], request: &RequestHeaders) -> io::Result<()> {
+    if let Some(reason) = request.error {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, reason, None));
+    }
+
+    try!(write!(s, "GET {} HTTP/1.1\r\n", url.serialize_path().unwrap_or("/".to_string())));
+    match request.host {
+        Some(ref host) => try!(write!(s, "Host: {}\r\n", host)),
+        None => try!(write!(s, "Host: {}\r\n", url.host().unwrap()))
+    }
+    match request.origin {
+        Some(Some(ref origin)) => try!(write!(s, "Origin: {}\r\n", origin)),
+        Some(None) => (),
+        None => try!(write!(s, "Origin: {}\r\n", url.serialize_no_fragment()))
+    }
+    for &(ref name, ref value) in request.headers.iter() {
+        try!(write!(s, "{}: {}\r\n", name, value));
+    }
+    try!(write!(s, "Sec-WebSocket-Key: {}\r\n", nonce));
+
+    try!(s.write_all(b"Upgrade: websocket\r\n"));
+    try!(s.write_all(b"Connection: Upgrade\r\n"));
+    try!(write!(s, "Sec-WebSocket-Version: {}\r\n", version));
+    if !protocols.is_empty() {
+        try!(write!(s, "Sec-WebSocket-Protocol: {}\r\n", protocols.connect(", ")));
+    }
+    if !extensions.is_empty() {
+        try!(write!(s, "Sec-WebSocket-Extensions: {}\r\n", extensions.connect(", ")));
+    }
+    s.write_all(b"\r\n")
+}
+
+// Handshake response as received from server
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub version: String,
+    pub status: Option<u16>,
+    pub reason: String,
+    // headers in order received, names as sent by server
+    pub headers: Vec<(String, String)>
+}
+
+impl Response {
+    // First value of header, name is case-insensitive
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, ref v)| &**v)
+    }
+
+    // All values of header, in order received
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.headers.iter().filter(|&&(ref n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, ref v)| &**v).collect()
+    }
+
+    // Subprotocol selected by server
+    #[inline] pub fn protocol(&self) -> Option<&str> {
+        self.get("Sec-WebSocket-Protocol")
+    }
+
+    // Extensions accepted by server, with their parameters
+    pub fn extensions(&self) -> Vec<&str> {
+        self.get_all("Sec-WebSocket-Extensions").into_iter().flat_map(|v| split_extensions(v).into_iter()).collect()
+    }
+
+    #[inline] pub fn cookies(&self) -> Vec<&str> {
+        self.get_all("Set-Cookie")
+    }
+
+    // Headers as a map for error reporting, multiple values are comma-joined
+    pub fn to_map(&self) -> BTreeMap<String, String> {
+        let mut map: BTreeMap<String, String> = BTreeMap::new();
+        for &(ref name, ref value) in self.headers.iter() {
+            match map.get_mut(name) {
+                Some(v) => { v.push_str(", "); v.push_str(&**value); continue; },
+                None => ()
+            }
+            map.insert(name.clone(), value.clone());
+        }
+        map
+    }
+}
+
+pub static MAX_RESPONSE_HEAD: usize = 16 << 10;
+pub static MAX_RESPONSE_HEADERS: usize = 100;
+
+// Incremental HTTP/1.x message head parser shared by response and request
+// parsers. Bytes are fed as they arrive, and only the head is consumed, so
+// that anything sent right after it stays in caller's buffer.
+struct HeadParser {
+    // complete lines already known not to finish the head
+    scanned: usize,
+    max_size: usize,
+    max_headers: usize
+}
+
+impl HeadParser {
+    fn new(max_size: usize, max_headers: usize) -> HeadParser {
+        HeadParser { scanned: 0, max_size: max_size, max_headers: max_headers }
+    }
+
+    // Returns start line, headers in order received and size of the head
+    fn parse(&mut self, buf: &[u8]) -> WSResult<Option<(String, Vec<(String, String)>, usize)>> {
+        let end = match self.find_end(buf) {
+            Some(end) => end,
+            None if buf.len() > self.max_size => return Err(WSError::handshake("message head is too large")),
+            None => return Ok(None)
+        };
+        if end > self.max_size {
+            return Err(WSError::handshake("message head is too large"));
+        }
+
+        let head = String::from_utf8_lossy(&buf[..end]);
+        let mut lines = head.split('\n').map(|l| l.trim_right_matches('\r'));
+        let start = lines.next().unwrap_or("").to_string();
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in lines.take_while(|l| !l.is_empty()) {
+            // Obsolete line folding: continuation of previous header value
+            if line.starts_with(" ") || line.starts_with("\t") {
+                match headers.last_mut() {
+                    Some(&mut (_, ref mut value)) => {
+                        value.push(' ');
+                        value.push_str(line.trim());
+                        continue;
+                    },
+                    None => return Err(WSError::handshake("invalid header line"))
+                }
+            }
+
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if is_token(name) => {
+                    if headers.len() >= self.max_headers {
+                        return Err(WSError::handshake("too many headers"));
+                    }
+                    headers.push((name.to_string(), value.trim().to_string()));
+                },
+                _ => return Err(WSError::handshake("invalid header line"))
+            }
+        }
+
+        self.scanned = 0;
+        Ok(Some((start, headers, end)))
+    }
+
+    // Position right after empty line ending the head (lines end with CRLF or bare LF)
+    fn find_end(&mut self, buf: &[u8]) -> Option<usize> {
+        let mut pos = self.scanned;
+        while let Some(nl) = buf[pos..].iter().position(|b| *b == b'\n') {
+            let next = pos + nl + 1;
+            let line = &buf[..next];
+            if line.ends_with(b"\n\n") || line.ends_with(b"\n\r\n") {
+                return Some(next);
+            }
+            pos = next;
+        }
+
+        self.scanned = pos;
+        None
+    }
+}
+
+// HTTP token (RFC 7230 section 3.2.6), e.g. header name
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| match b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false
+    })
+}
+
+// Incremental handshake response parser
+pub struct ResponseParser {
+    head: HeadParser
+}
+
+impl ResponseParser {
+    pub fn new() -> ResponseParser {
+        ResponseParser::with_limits(MAX_RESPONSE_HEAD, MAX_RESPONSE_HEADERS)
+    }
+
+    pub fn with_limits(max_size: usize, max_headers: usize) -> ResponseParser {
+        ResponseParser { head: HeadParser::new(max_size, max_headers) }
+    }
+
+    // `buf` holds all bytes received so far, returns parsed response
+    // and size of its head, or None if more bytes are needed
+    pub fn parse(&mut self, buf: &[u8]) -> WSResult<Option<(Response, usize)>> {
+        let (line, headers, end) = match try!(self.head.parse(buf)) {
+            Some(head) => head,
+            None => return Ok(None)
+        };
+
+        let mut parts = line.splitn(3, ' ');
+        match (parts.next(), parts.next().and_then(|s| if s.len() == 3 { s.parse::<u16>().ok() } else { None }), parts.next()) {
+            (Some(version), Some(status), reason) if version.starts_with("HTTP/1.") => {
+                let response = Response { version: version.to_string(), status: Some(status), reason: reason.unwrap_or("").to_string(), headers: headers };
+                Ok(Some((response, end)))
+            },
+            _ => Err(WSError::handshake("invalid response status line"))
+        }
+    }
+}
+
+// Handshake request as received by server
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    // headers in order received, names as sent by client
+    pub headers: Vec<(String, String)>
+}
+
+// Incremental handshake request parser for server side,
+// with the same limits as responses have
+pub struct RequestParser {
+    head: HeadParser
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        RequestParser::with_limits(MAX_RESPONSE_HEAD, MAX_RESPONSE_HEADERS)
+    }
+
+    pub fn with_limits(max_size: usize, max_headers: usize) -> RequestParser {
+        RequestParser { head: HeadParser::new(max_size, max_headers) }
+    }
+
+    // Same as `ResponseParser::parse()`
+    pub fn parse(&mut self, buf: &[u8]) -> WSResult<Option<(Request, usize)>> {
+        let (line, headers, end) = match try!(self.head.parse(buf)) {
+            Some(head) => head,
+            None => return Ok(None)
+        };
+
+        let mut parts = line.split(' ');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), Some(version), None) if is_token(method) && !path.is_empty() && version.starts_with("HTTP/1.") => {
+                let request = Request { method: method.to_string(), path: path.to_string(), version: version.to_string(), headers: headers };
+                Ok(Some((request, end)))
+            },
+            _ => Err(WSError::handshake("invalid request line"))
+        }
+    }
+}
+
+// Checks response status and Sec-WebSocket-Accept value (`accept` is encoded nonce)
+pub fn check_response(response: Response, accept: &str) -> WSResult<Response> {
+    let accepted = response.get("Sec-WebSocket-Accept").map(|r| accept == r).unwrap_or(false);
+    let reason = match response.status {
+        Some(101) if accepted => return Ok(response),
+        Some(101) => "missing Sec-WebSocket-Accept header in response",
+        Some(_) => "invalid response status",
+        None => "missing response status"
+    };
+
+    Err(WSError::Handshake { status: response.status, headers: response.to_map(), reason: reason })
+}
+
+// Location of redirect response (301, 302, 307 or 308) handshake failed with
+pub fn redirect_location(err: &WSError) -> Option<&str> {
+    match *err {
+        WSError::Handshake { status: Some(301...302), ref headers, .. } |
+        WSError::Handshake { status: Some(307...308), ref headers, .. } =>
+            headers.iter().find(|&(name, _)| name.eq_ignore_ascii_case("location")).map(|(_, value)| &**value),
+        _ => None
+    }
+}
+
+// Resolves redirect location relative to current URL,
+// http(s) URLs are mapped to ws(s) ones
+pub fn redirect_url(base: &Url, location: &str) -> WSResult<Url> {
+    let mut url = match UrlParser::new().base_url(base).parse(location) {
+        Ok(url) => url,
+        Err(_) => return Err(WSError::handshake("invalid redirect location"))
+    };
+
+    url.scheme = match &*url.scheme {
+        "ws" | "http" => "ws".to_string(),
+        "wss" | "https" => "wss".to_string(),
+        _ => return Err(WSError::handshake("unsupported redirect location scheme"))
+    };
+    Ok(url)
+}
+
+// Server must select at most one subprotocol, and only one of offered
+// ones (RFC 6455 section 4.1), returns selected one
+pub fn check_protocol(response: &Response, offered: &[String]) -> WSResult<Option<String>> {
+    let selected = response.get_all("Sec-WebSocket-Protocol");
+    let reason = match selected.len() {
+        0 => return Ok(None),
+        1 if selected[0].contains(',') => "more than one subprotocol selected by server",
+        1 if offered.iter().any(|p| *p == selected[0]) => return Ok(Some(selected[0].to_string())),
+        1 => "subprotocol not offered by client selected by server",
+        _ => "more than one subprotocol selected by server"
+    };
+
+    Err(WSError::Handshake { status: response.status, headers: response.to_map(), reason: reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use url::Url;
+    use error::{WSError, WSResult};
+    use super::{RequestHeaders, ResponseParser, RequestParser, write_request};
+
+    fn request(headers: &RequestHeaders) -> io::Result<String> {
+        let url = Url::parse("ws://example.com/chat").unwrap();
+        let mut out = Vec::new();
+        try!(write_request(&mut out, &url, "key", 13, &[], &[], headers));
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn extra_headers_are_written() {
+        let req = request(&RequestHeaders::new().header("Authorization", "Bearer token").no_origin()).unwrap();
+        assert!(req.contains("\r\nAuthorization: Bearer token\r\n"));
+        assert!(!req.contains("Origin:"));
+    }
+
+    #[test]
+    fn line_breaks_in_values_are_rejected() {
+        for value in ["a\r\nX-Injected: 1", "a\nb", "a\rb"].iter() {
+            let err = request(&RequestHeaders::new().header("X-Test", *value)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(request(&RequestHeaders::new().host("example.com\r\nX-Injected: 1")).is_err());
+        assert!(request(&RequestHeaders::new().origin("http://a\r\n")).is_err());
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        for name in ["", "X Test", "X-Test:", "X-Test\r\nX-Injected"].iter() {
+            assert!(request(&RequestHeaders::new().header(*name, "1")).is_err(), "name {:?}", name);
+        }
+    }
+
+    #[test]
+    fn credentials_are_dropped_for_other_host() {
+        let headers = RequestHeaders::new().host("example.com").header("authorization", "Bearer token")
+            .header("Cookie", "sid=1").header("X-Trace", "1").for_other_host();
+        let req = request(&headers).unwrap();
+        assert!(req.contains("\r\nHost: example.com\r\n"));
+        assert!(req.contains("\r\nX-Trace: 1\r\n"));
+        assert!(!req.contains("Bearer") && !req.contains("sid="));
+    }
+
+    #[test]
+    fn nothing_is_written_on_error() {
+        let url = Url::parse("ws://example.com/chat").unwrap();
+        let mut out = Vec::new();
+        let headers = RequestHeaders::new().header("X-Ok", "1").header("X-Bad", "\r\n");
+        assert!(write_request(&mut out, &url, "key", 13, &[], &[], &headers).is_err());
+        assert!(out.is_empty());
+    }
+
+    static RESPONSE: &'static [u8] = b"HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+        \r\n";
+
+    fn handshake_reason<T>(res: WSResult<T>) -> &'static str {
+        match res {
+            Err(WSError::Handshake { reason, .. }) => reason,
+            _ => panic!("handshake error expected")
+        }
+    }
+
+    #[test]
+    fn response_fed_byte_by_byte() {
+        let mut parser = ResponseParser::new();
+        for n in 0..RESPONSE.len() {
+            assert!(parser.parse(&RESPONSE[..n]).unwrap().is_none(), "parsed from {} bytes", n);
+        }
+        let (response, size) = parser.parse(RESPONSE).unwrap().unwrap();
+        assert_eq!(size, RESPONSE.len());
+        assert_eq!(response.version, "HTTP/1.1");
+        assert_eq!(response.status, Some(101));
+        assert_eq!(response.reason, "Switching Protocols");
+        assert_eq!(response.get("sec-websocket-accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    }
+
+    #[test]
+    fn bytes_after_head_are_not_consumed() {
+        let mut buf = RESPONSE.to_vec();
+        buf.push_all(b"\x81\x02Hi");
+        let (_, size) = ResponseParser::new().parse(&*buf).unwrap().unwrap();
+        assert_eq!(size, RESPONSE.len());
+        assert_eq!(&buf[size..], &b"\x81\x02Hi"[..]);
+    }
+
+    #[test]
+    fn bare_lf_line_endings() {
+        let buf = b"HTTP/1.1 101 OK\nUpgrade: websocket\n\n";
+        let (response, size) = ResponseParser::new().parse(buf).unwrap().unwrap();
+        assert_eq!(size, buf.len());
+        assert_eq!(response.get("Upgrade"), Some("websocket"));
+    }
+
+    #[test]
+    fn folded_header() {
+        let buf = b"HTTP/1.1 101 OK\r\nX-Long: first\r\n  second\r\n\tthird\r\nX-Next: 1\r\n\r\n";
+        let (response, _) = ResponseParser::new().parse(buf).unwrap().unwrap();
+        assert_eq!(response.get("X-Long"), Some("first second third"));
+        assert_eq!(response.get("X-Next"), Some("1"));
+        assert_eq!(handshake_reason(ResponseParser::new().parse(b"HTTP/1.1 101 OK\r\n folded\r\n\r\n")), "invalid header line");
+    }
+
+    #[test]
+    fn duplicate_headers() {
+        let buf = b"HTTP/1.1 101 OK\r\nSec-WebSocket-Extensions: a\r\nsec-websocket-extensions: b; x=1\r\n\r\n";
+        let (response, _) = ResponseParser::new().parse(buf).unwrap().unwrap();
+        assert_eq!(response.get("Sec-WebSocket-Extensions"), Some("a"));
+        assert_eq!(response.get_all("Sec-WebSocket-Extensions"), vec!["a", "b; x=1"]);
+        assert_eq!(response.extensions(), vec!["a", "b; x=1"]);
+    }
+
+    #[test]
+    fn head_size_limit() {
+        let mut parser = ResponseParser::with_limits(64, 100);
+        let mut buf = b"HTTP/1.1 101 OK\r\n".to_vec();
+        while buf.len() <= 64 {
+            buf.push_all(b"X-Padding: 1234567890\r\n");
+        }
+        // limit is hit before the end of head arrives
+        assert_eq!(handshake_reason(parser.parse(&*buf)), "message head is too large");
+
+        buf.push_all(b"\r\n");
+        assert_eq!(handshake_reason(ResponseParser::with_limits(64, 100).parse(&*buf)), "message head is too large");
+    }
+
+    #[test]
+    fn header_count_limit() {
+        let buf = b"HTTP/1.1 101 OK\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(ResponseParser::with_limits(1024, 3).parse(buf).unwrap().is_some());
+        assert_eq!(handshake_reason(ResponseParser::with_limits(1024, 2).parse(buf)), "too many headers");
+    }
+
+    #[test]
+    fn invalid_status_line() {
+        for line in ["HTTP/1.1 OK\r\n\r\n", "HTTP/1.1 1010 OK\r\n\r\n", "ICY 200 OK\r\n\r\n", "\r\n\r\n"].iter() {
+            assert_eq!(handshake_reason(ResponseParser::new().parse(line.as_bytes())), "invalid response status line");
+        }
+    }
+
+    #[test]
+    fn invalid_header_line() {
+        for head in ["HTTP/1.1 101 OK\r\nNo colon\r\n\r\n", "HTTP/1.1 101 OK\r\nBad name: 1\r\n\r\n"].iter() {
+            assert_eq!(handshake_reason(ResponseParser::new().parse(head.as_bytes())), "invalid header line");
+        }
+    }
+
+    #[test]
+    fn request_head() {
+        let buf = b"GET /chat HTTP/1.1\r\nHost: example.com\r\n\r\nrest";
+        let (request, size) = RequestParser::new().parse(buf).unwrap().unwrap();
+        assert_eq!(size, buf.len() - 4);
+        assert_eq!((&*request.method, &*request.path, &*request.version), ("GET", "/chat", "HTTP/1.1"));
+        assert_eq!(request.headers, vec![("Host".to_string(), "example.com".to_string())]);
+        assert_eq!(handshake_reason(RequestParser::new().parse(b"GET /chat\r\n\r\n")), "invalid request line");
+    }
+}
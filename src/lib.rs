@@ -4,20 +4,53 @@
 #![feature(test)]
 
 extern crate url;
-extern crate openssl;
+#[cfg(feature = "openssl")] extern crate openssl;
+#[cfg(feature = "rustls")] extern crate rustls;
+#[cfg(feature = "rustls")] extern crate "rustls-pemfile" as rustls_pemfile;
+#[cfg(feature = "rustls")] extern crate "rustls-native-certs" as rustls_native_certs;
+#[cfg(feature = "rustls")] extern crate "webpki-roots" as webpki_roots;
 extern crate "rustc-serialize" as rustc_serialize;
 extern crate "sha1-hasher" as sha1;
 extern crate rand;
+extern crate flate2;
+#[cfg(feature = "tokio")] extern crate tokio;
+#[cfg(feature = "futures")] extern crate futures;
 #[macro_use] extern crate bitflags;
 
 #[cfg(test)]
 extern crate test;
 
+// TLS backends are mutually exclusive, and `no-tls` means neither of them
+#[cfg(all(feature = "openssl", feature = "rustls"))]
+compile_error!("features \"openssl\" and \"rustls\" can't be enabled together, disable default features to use rustls");
+#[cfg(all(feature = "no-tls", any(feature = "openssl", feature = "rustls")))]
+compile_error!("feature \"no-tls\" can't be combined with a TLS backend, disable default features");
+
 pub use socket::WebSocket;
+pub use server::WebSocketServer;
 pub use message::{WSMessage, WSStatusCode};
+pub use error::{WSError, WSResult};
+pub use codec::FrameCodec;
+pub use handshake::{RequestHeaders, Response};
+pub use tls::{TlsConfig, TlsVersion};
+pub use split::{WSReader, WSWriter, WSReaderMessages, SplitError};
+#[cfg(feature = "tokio")] pub use asynchronous::AsyncWebSocket;
 
+pub mod error;
 pub mod nonce;
 pub mod message;
 pub mod stream;
 pub mod socket;
-
+pub mod server;
+pub mod extension;
+pub mod deflate;
+mod validate;
+pub mod handshake;
+pub mod frame;
+pub mod codec;
+pub mod proxy;
+pub mod tls;
+#[cfg(feature = "openssl")] mod openssl_tls;
+#[cfg(feature = "rustls")] mod rustls_tls;
+mod split;
+#[cfg(feature = "tokio")] pub mod asynchronous;
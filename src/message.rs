@@ -1,4 +1,6 @@
 use std::str::FromStr;
+use std::fmt;
+use std::error::Error;
 use std::num::{FromPrimitive, ToPrimitive};
 use rustc_serialize::json::{Json, ToJson};
 
@@ -347,8 +349,22 @@ impl ToJson for WSMessage {
     }
 }
 
-#[derive(Copy)]
-pub struct WSMessageParseError;
+#[derive(Copy, Debug)]
+pub struct WSMessageParseError {
+    pub reason: &'static str
+}
+
+impl fmt::Display for WSMessageParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid message: {}", self.reason)
+    }
+}
+
+impl Error for WSMessageParseError {
+    fn description(&self) -> &str {
+        self.reason
+    }
+}
 
 impl FromStr for WSMessage {
     type Err = WSMessageParseError;
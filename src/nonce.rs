@@ -15,11 +15,15 @@ impl Nonce {
     }
 
     fn generate<R: Rng>(r: &mut R) -> Nonce {
-        let mut nonce = [0u8; 10];
+        let mut nonce = [0u8; 16];
         r.fill_bytes(nonce.as_mut_slice());
         Nonce(nonce.to_base64(base64::STANDARD))
     }
 
+    pub fn from_key(key: &str) -> Nonce {
+        Nonce(key.to_string())
+    }
+
     pub fn encode(self) -> Nonce {
         let mut sha1 = Sha1::new();
         sha1.write(self.0.as_bytes()).unwrap();
@@ -0,0 +1,156 @@
+use openssl::ssl::{SslMethod, SslStream, SslContext, Ssl, SSL_VERIFY_PEER, SSL_VERIFY_NONE,
+                   SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1};
+use openssl::ssl::error::{SslError, OpensslError};
+use openssl::x509::{X509, X509FileType, X509StoreContext};
+use openssl::pkcs12::Pkcs12;
+use openssl::nid::Nid;
+use std::net::TcpStream;
+use std::io::{Read, self};
+use std::fs::File;
+
+use error::{WSError, WSResult};
+use tls::{TlsConfig, TlsVersion, ClientIdentity, is_ip_address, ip_octets, hostname_matches, is_cert_rejection};
+
+pub type TlsStream = SslStream<TcpStream>;
+
+// TLS over connected socket with OpenSSL
+pub fn connect(sock: TcpStream, hostname: &str, tls: &TlsConfig) -> WSResult<TlsStream> {
+    let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(tls_error));
+
+    let mut options = SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3;
+    match tls.min_version {
+        Some(TlsVersion::Tls11) => options = options | SSL_OP_NO_TLSV1,
+        Some(TlsVersion::Tls12) => options = options | SSL_OP_NO_TLSV1 | SSL_OP_NO_TLSV1_1,
+        _ => ()
+    }
+    ctx.set_options(options);
+
+    let name = tls.name_for(hostname).to_string();
+    if tls.insecure {
+        ctx.set_verify(SSL_VERIFY_NONE, None);
+    } else {
+        try!(ctx.set_default_verify_paths().map_err(tls_error));
+        if let Some(ref path) = tls.ca_file {
+            try!(ctx.set_CA_file(path).map_err(tls_error));
+        }
+        if tls.verify_hostname {
+            ctx.set_verify_with_data(SSL_VERIFY_PEER, verify_hostname, name.clone());
+        } else {
+            ctx.set_verify(SSL_VERIFY_PEER, None);
+        }
+    }
+
+    if let Some(ref identity) = tls.client_identity {
+        try!(set_client_identity(&mut ctx, identity));
+    }
+
+    let ssl = try!(Ssl::new(&ctx).map_err(tls_error));
+    if tls.sni && !is_ip_address(&*name) {
+        try!(ssl.set_hostname(&*name).map_err(tls_error));
+    }
+
+    SslStream::new_from(ssl, sock).map_err(|e| {
+        if tls.client_identity.is_some() && received_alert(&e).map(is_cert_rejection).unwrap_or(false) {
+            WSError::client_certificate("server rejected client certificate", e)
+        } else {
+            tls_error(e)
+        }
+    })
+}
+
+fn set_client_identity(ctx: &mut SslContext, identity: &ClientIdentity) -> WSResult<()> {
+    match *identity {
+        ClientIdentity::Pem { ref cert_chain, ref key } => {
+            try!(ctx.set_certificate_chain_file(cert_chain, X509FileType::PEM).map_err(|e| WSError::client_certificate("can't load client certificate chain", e)));
+            try!(ctx.set_private_key_file(key, X509FileType::PEM).map_err(|e| WSError::client_certificate("can't load client private key", e)));
+        },
+        ClientIdentity::Pkcs12 { ref path, ref password } => {
+            let mut der = Vec::new();
+            try!(File::open(path).and_then(|mut f| f.read_to_end(&mut der)).map_err(|e| WSError::client_certificate("can't read PKCS#12 archive", e)));
+            let parsed = try!(Pkcs12::from_der(&*der).and_then(|p| p.parse(&**password)).map_err(|e| WSError::client_certificate("can't parse PKCS#12 archive", e)));
+
+            try!(ctx.set_certificate(&parsed.cert).map_err(|e| WSError::client_certificate("can't load client certificate", e)));
+            try!(ctx.set_private_key(&parsed.pkey).map_err(|e| WSError::client_certificate("can't load client private key", e)));
+            for cert in parsed.chain.into_iter() {
+                try!(ctx.add_extra_chain_cert(&cert).map_err(|e| WSError::client_certificate("can't load client certificate chain", e)));
+            }
+        }
+    }
+
+    ctx.check_private_key().map_err(|e| WSError::client_certificate("client private key doesn't match certificate", e))
+}
+
+fn tls_error(err: SslError) -> WSError {
+    WSError::Tls(Box::new(err))
+}
+
+// OpenSSL reports alerts received from peer with SSL_R_SSLV3_ALERT_* and
+// SSL_R_TLSV1*_ALERT_* reason codes (1000 + alert description), which
+// openssl crate gives by their names
+static ALERT_REASONS: &'static [(&'static str, u8)] = &[
+    ("sslv3 alert bad certificate", 42),
+    ("sslv3 alert unsupported certificate", 43),
+    ("sslv3 alert certificate revoked", 44),
+    ("sslv3 alert certificate expired", 45),
+    ("sslv3 alert certificate unknown", 46),
+    ("tlsv1 alert unknown ca", 48),
+    ("tlsv1 alert access denied", 49),
+    ("tlsv13 alert certificate required", 116)
+];
+
+// Error of the first read after handshake: with TLS 1.3 server checks client
+// certificate after client finishes handshake, so it may be rejected only then
+pub fn read_error(err: io::Error, has_identity: bool) -> WSError {
+    let rejected = err.get_ref().and_then(|e| e.downcast_ref::<SslError>()).and_then(received_alert).map(is_cert_rejection).unwrap_or(false);
+    if has_identity && rejected {
+        WSError::client_certificate("server rejected client certificate", err)
+    } else {
+        WSError::Io(err)
+    }
+}
+
+// Description of alert from peer handshake failed with, if any
+fn received_alert(err: &SslError) -> Option<u8> {
+    let errors = match *err {
+        SslError::OpenSslErrors(ref errors) => errors,
+        _ => return None
+    };
+
+    errors.iter().filter_map(|e| match *e {
+        OpensslError::UnknownError { ref reason, .. } => ALERT_REASONS.iter().find(|&&(name, _)| name == &**reason).map(|&(_, alert)| alert)
+    }).next()
+}
+
+// Checks server certificate (chain is verified by OpenSSL) is issued for `name`,
+// IP addresses are matched against IP address SANs only (RFC 6125 section 1.7.2)
+fn verify_hostname(preverify_ok: bool, x509_ctx: &X509StoreContext, name: &String) -> bool {
+    if !preverify_ok || x509_ctx.error_depth() != 0 {
+        return preverify_ok;
+    }
+
+    match (x509_ctx.get_current_cert(), ip_octets(&**name)) {
+        (Some(cert), Some(ip)) => cert_ips(&cert).iter().any(|a| *a == ip),
+        (Some(cert), None) => cert_names(&cert).iter().any(|n| hostname_matches(&**n, &**name)),
+        (None, _) => false
+    }
+}
+
+// IP addresses certificate is issued for, in network byte order
+fn cert_ips(cert: &X509) -> Vec<Vec<u8>> {
+    cert.subject_alt_names()
+        .map(|names| names.iter().filter_map(|n| n.ipaddress().map(|v| v.to_vec())).collect::<Vec<Vec<u8>>>())
+        .unwrap_or(Vec::new())
+}
+
+// DNS names certificate is issued for, common name is used only without
+// subject alternative names
+fn cert_names(cert: &X509) -> Vec<String> {
+    let alt_names = cert.subject_alt_names()
+        .map(|names| names.iter().filter_map(|n| n.dns().map(|v| v.to_string())).collect::<Vec<String>>())
+        .unwrap_or(Vec::new());
+
+    if !alt_names.is_empty() {
+        return alt_names;
+    }
+    cert.subject_name().text_by_nid(Nid::CN).map(|cn| vec![cn.to_string()]).unwrap_or(Vec::new())
+}
@@ -0,0 +1,165 @@
+use rustls::{self, ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned,
+             Certificate, PrivateKey, OwnedTrustAnchor, CertificateError};
+use rustls::client::{ServerCertVerifier, ServerCertVerified, WebPkiVerifier};
+use std::net::TcpStream;
+use std::io::{BufReader, self};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use error::{WSError, WSResult};
+use tls::{TlsConfig, ClientIdentity, is_cert_rejection};
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+// TLS over connected socket with rustls, handshake is completed before return
+// (rustls supports TLS 1.2 and 1.3 only, so `min_version` is always satisfied)
+pub fn connect(mut sock: TcpStream, hostname: &str, tls: &TlsConfig) -> WSResult<TlsStream> {
+    let builder = try!(ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13, &rustls::version::TLS12])
+        .map_err(tls_error))
+        .with_custom_certificate_verifier(try!(verifier(tls)));
+
+    let mut config = match tls.client_identity {
+        Some(ref identity) => {
+            let (certs, key) = try!(load_identity(identity));
+            try!(builder.with_client_auth_cert(certs, key).map_err(|e| WSError::client_certificate("can't load client certificate", e)))
+        },
+        None => builder.with_no_client_auth()
+    };
+    // SNI is never sent for IP addresses
+    config.enable_sni = tls.sni;
+
+    let name = try!(ServerName::try_from(tls.name_for(hostname)).map_err(|_| WSError::handshake("invalid TLS server name")));
+    let mut conn = try!(ClientConnection::new(Arc::new(config), name).map_err(tls_error));
+
+    // With TLS 1.3 server checks client certificate after client finishes handshake,
+    // so its rejection may only show up on the first read (see `read_error()`)
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(&mut sock) {
+            return Err(handshake_error(e, tls.client_identity.is_some()));
+        }
+    }
+
+    Ok(StreamOwned::new(conn, sock))
+}
+
+fn verifier(tls: &TlsConfig) -> WSResult<Arc<ServerCertVerifier>> {
+    if tls.insecure {
+        return Ok(Arc::new(AcceptAny));
+    }
+
+    // System CA store, as with OpenSSL; bundled Mozilla roots are used
+    // only if there's none (e.g. in minimal containers)
+    let mut roots = RootCertStore::empty();
+    let native: Vec<Vec<u8>> = rustls_native_certs::load_native_certs().map(|certs| certs.into_iter().map(|c| c.0).collect()).unwrap_or(Vec::new());
+    roots.add_parsable_certificates(&*native);
+    if roots.is_empty() {
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        }));
+    }
+    if let Some(ref path) = tls.ca_file {
+        for cert in try!(read_certs(path)).into_iter() {
+            try!(roots.add(&cert).map_err(|e| WSError::Tls(Box::new(e))));
+        }
+    }
+
+    let inner = WebPkiVerifier::new(roots, None);
+    if tls.verify_hostname {
+        Ok(Arc::new(inner))
+    } else {
+        Ok(Arc::new(IgnoreHostname(inner)))
+    }
+}
+
+fn load_identity(identity: &ClientIdentity) -> WSResult<(Vec<Certificate>, PrivateKey)> {
+    match *identity {
+        ClientIdentity::Pem { ref cert_chain, ref key } => {
+            let certs = try!(read_certs(cert_chain).map_err(|e| WSError::client_certificate("can't load client certificate chain", e)));
+            if certs.is_empty() {
+                return Err(WSError::ClientCertificate { reason: "no certificates in client certificate chain", cause: None });
+            }
+
+            let mut reader = BufReader::new(try!(File::open(key).map_err(|e| WSError::client_certificate("can't load client private key", e))));
+            let items = try!(rustls_pemfile::read_all(&mut reader).map_err(|e| WSError::client_certificate("can't load client private key", e)));
+            let key = items.into_iter().filter_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None
+            }).next();
+
+            match key {
+                Some(key) => Ok((certs, key)),
+                None => Err(WSError::ClientCertificate { reason: "no private key in client key file", cause: None })
+            }
+        },
+        ClientIdentity::Pkcs12 { .. } => Err(WSError::ClientCertificate { reason: "PKCS#12 is not supported by rustls backend, use PEM files", cause: None })
+    }
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(try!(File::open(path)));
+    let certs = try!(rustls_pemfile::certs(&mut reader));
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// Alerts from server that mean it didn't accept our certificate are reported
+// as client certificate errors, other TLS failures as is
+fn handshake_error(err: io::Error, has_identity: bool) -> WSError {
+    if has_identity && received_alert(&err).map(is_cert_rejection).unwrap_or(false) {
+        return WSError::client_certificate("server rejected client certificate", err);
+    }
+
+    match err.get_ref().map(|e| e.is::<rustls::Error>()) {
+        Some(true) => WSError::Tls(Box::new(err)),
+        _ => WSError::Io(err)
+    }
+}
+
+// Error of the first read after handshake, certificate rejection
+// is reported the same way as during handshake
+pub fn read_error(err: io::Error, has_identity: bool) -> WSError {
+    if has_identity && received_alert(&err).map(is_cert_rejection).unwrap_or(false) {
+        WSError::client_certificate("server rejected client certificate", err)
+    } else {
+        WSError::Io(err)
+    }
+}
+
+// Description of alert server failed connection with, if any
+fn received_alert(err: &io::Error) -> Option<u8> {
+    match err.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(&rustls::Error::AlertReceived(alert)) => Some(alert.get_u8()),
+        _ => None
+    }
+}
+
+fn tls_error(err: rustls::Error) -> WSError {
+    WSError::Tls(Box::new(err))
+}
+
+// Accepts any certificate, see `TlsConfig::insecure()`
+struct AcceptAny;
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(&self, _: &Certificate, _: &[Certificate], _: &ServerName,
+                          _: &mut Iterator<Item = &[u8]>, _: &[u8], _: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Verifies certificate chain, but not the name it's issued for
+struct IgnoreHostname(WebPkiVerifier);
+
+impl ServerCertVerifier for IgnoreHostname {
+    fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate], server_name: &ServerName,
+                          scts: &mut Iterator<Item = &[u8]>, ocsp_response: &[u8], now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        match self.0.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => Ok(ServerCertVerified::assertion()),
+            res => res
+        }
+    }
+}
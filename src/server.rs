@@ -0,0 +1,289 @@
+use std::io::{Read, Write, BufRead, BufStream};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use std::collections::BTreeMap;
+use std::ascii::AsciiExt;
+use std::slice::SliceConcatExt;
+use url::Url;
+use rustc_serialize::base64::FromBase64;
+
+use nonce::Nonce;
+use socket::WebSocket;
+use error::{WSError, WSResult};
+use extension::{Extension, extension_name, split_extensions, check_conflicts};
+use handshake::RequestParser;
+
+// Chooses subprotocol from client's offers, in client's order of preference
+pub type ProtocolSelector = Fn(&[&str]) -> Option<String> + Send + Sync;
+
+pub struct WebSocketServer {
+    listener: TcpListener,
+    protocols: Option<Vec<String>>,
+    selector: Option<Box<ProtocolSelector>>,
+    // each connection gets its own extension instances
+    extensions: Vec<Box<Fn() -> Box<Extension + Send> + Send + Sync>>,
+    handshake_timeout: Option<Duration>
+}
+
+impl WebSocketServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> WSResult<WebSocketServer> {
+        Ok(WebSocketServer {
+            listener: try!(TcpListener::bind(addr)),
+            protocols: None,
+            selector: None,
+            extensions: Vec::new(),
+            handshake_timeout: Some(Duration::from_secs(10))
+        })
+    }
+
+    pub fn protocols(mut self, protocols: &[&str]) -> WebSocketServer {
+        self.protocols = Some(protocols.iter().map(|v| v.to_string()).collect());
+        self
+    }
+
+    // Custom subprotocol selection, takes precedence over `protocols()`
+    pub fn select_protocol<F>(mut self, selector: F) -> WebSocketServer where F: Fn(&[&str]) -> Option<String> + Send + Sync + 'static {
+        self.selector = Some(Box::new(selector));
+        self
+    }
+
+    pub fn extension<F>(mut self, factory: F) -> WebSocketServer where F: Fn() -> Box<Extension + Send> + Send + Sync + 'static {
+        self.extensions.push(Box::new(factory));
+        self
+    }
+
+    // Limit time client may take to send handshake request (10 seconds by default),
+    // so that idle clients don't hold up accepting other connections
+    pub fn handshake_timeout(mut self, timeout: Option<Duration>) -> WebSocketServer {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn accept(&self) -> WSResult<WebSocket<TcpStream>> {
+        let (sock, _) = try!(self.listener.accept());
+        // Timeout is socket's property, so it's cleared through another handle
+        try!(sock.set_read_timeout(self.handshake_timeout));
+        let handle = try!(sock.try_clone());
+
+        let extensions = self.extensions.iter().map(|f| f()).collect();
+        let ws = try!(match self.selector {
+            Some(ref selector) => accept_with_selector(sock, &**selector, extensions),
+            None => {
+                let protos = self.protocols.as_ref().map(|v| v.iter().map(|v| &**v).collect::<Vec<&str>>());
+                accept_with_options(sock, protos.as_ref().map(|v| &**v), extensions)
+            }
+        });
+
+        try!(handle.set_read_timeout(None));
+        Ok(ws)
+    }
+
+    pub fn incoming(&self) -> WSConnections {
+        WSConnections { server: self }
+    }
+}
+
+pub struct WSConnections<'a> {
+    server: &'a WebSocketServer
+}
+
+impl<'a> Iterator for WSConnections<'a> {
+    type Item = WSResult<WebSocket<TcpStream>>;
+    fn next(&mut self) -> Option<WSResult<WebSocket<TcpStream>>> {
+        Some(self.server.accept())
+    }
+}
+
+#[inline] pub fn accept<S: Read + Write>(stream: S) -> WSResult<WebSocket<S>> {
+    accept_with_options(stream, None, Vec::new())
+}
+
+// Subprotocol is the first of client's ones supported by server
+pub fn accept_with_options<S: Read + Write>(stream: S, protocols: Option<&[&str]>, extensions: Vec<Box<Extension + Send>>) -> WSResult<WebSocket<S>> {
+    let ours = protocols.unwrap_or(&[][..]);
+    accept_with_selector(stream, &|theirs: &[&str]| theirs.iter().find(|p| ours.iter().any(|o| o == *p)).map(|p| p.to_string()), extensions)
+}
+
+// Subprotocol is chosen by `selector` from client's offers, selection of
+// a protocol client didn't offer is ignored
+pub fn accept_with_selector<S: Read + Write>(stream: S, selector: &Fn(&[&str]) -> Option<String>, mut extensions: Vec<Box<Extension + Send>>) -> WSResult<WebSocket<S>> {
+    let mut s = BufStream::new(stream);
+
+    let (path, headers) = match read_request(&mut s) {
+        Ok(req) => req,
+        Err(WSError::Io(e)) => return Err(WSError::Io(e)),
+        Err(_) => return Err(reject(&mut s, 400, BTreeMap::new(), "malformed upgrade request"))
+    };
+
+    if !header_has_token(&headers, "upgrade", "websocket") || !header_has_token(&headers, "connection", "upgrade") {
+        return Err(reject(&mut s, 400, headers, "not a websocket upgrade request"));
+    }
+
+    if headers.get("sec-websocket-version").and_then(|v| v.parse::<u32>().ok()) != Some(13) {
+        return Err(reject(&mut s, 426, headers, "unsupported Sec-WebSocket-Version"));
+    }
+
+    let key = match headers.get("sec-websocket-key") {
+        Some(k) if k.from_base64().map(|v| v.len() == 16).unwrap_or(false) => Some(k.clone()),
+        _ => None
+    };
+    let key = match key {
+        Some(k) => k,
+        None => return Err(reject(&mut s, 400, headers, "missing or invalid Sec-WebSocket-Key header in request"))
+    };
+
+    let protocol = {
+        let offered: Vec<&str> = headers.get("sec-websocket-protocol").map(|v| v.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()).collect()).unwrap_or(Vec::new());
+        if offered.is_empty() {
+            None
+        } else {
+            selector(&*offered).and_then(|p| if offered.iter().any(|o| *o == &*p) { Some(p) } else { None })
+        }
+    };
+
+    // Client may offer the same extension several times, most preferred first
+    // (RFC 7692 section 5), so each supported extension is activated with the first
+    // offer it accepts. Extensions are activated in client's order of preference.
+    let mut active: Vec<Box<Extension + Send>> = Vec::new();
+    let mut responses = Vec::new();
+    for offer in headers.get("sec-websocket-extensions").map(|v| split_extensions(&**v)).unwrap_or(Vec::new()).into_iter() {
+        let name = extension_name(offer);
+        if active.iter().any(|e| e.name() == name) {
+            continue;
+        }
+        if let Some(idx) = extensions.iter().position(|e| e.name() == name) {
+            if let Some(response) = extensions[idx].respond(offer) {
+                // Skip extensions conflicting with already accepted ones
+                active.push(extensions.remove(idx));
+                if check_conflicts(&*active).is_ok() {
+                    responses.push(response);
+                } else {
+                    active.pop();
+                }
+            }
+        }
+    }
+
+    let url = match Url::parse(&*format!("ws://{}{}", headers.get("host").map(|v| &**v).unwrap_or("localhost"), path)) {
+        Ok(url) => url,
+        Err(_) => return Err(reject(&mut s, 400, headers, "invalid request target"))
+    };
+
+    try!(s.write_all(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    try!(s.write_all(b"Upgrade: websocket\r\n"));
+    try!(s.write_all(b"Connection: Upgrade\r\n"));
+    try!(write!(s, "Sec-WebSocket-Accept: {}\r\n", &*Nonce::from_key(&*key).encode()));
+    if let Some(ref proto) = protocol {
+        try!(write!(s, "Sec-WebSocket-Protocol: {}\r\n", proto));
+    }
+    if !responses.is_empty() {
+        try!(write!(s, "Sec-WebSocket-Extensions: {}\r\n", responses.connect(", ")));
+    }
+    try!(s.write_all(b"\r\n"));
+    try!(s.flush());
+
+    Ok(WebSocket::from_handshaken(s, url, 13, protocol, active))
+}
+
+// Reads request head up to empty line, leaving anything after it buffered
+fn read_request<S: Read + Write>(s: &mut BufStream<S>) -> WSResult<(String, BTreeMap<String, String>)> {
+    let mut parser = RequestParser::new();
+    let mut head = Vec::new();
+    let mut request = None;
+    while request.is_none() {
+        let used = {
+            let buf = try!(s.fill_buf());
+            if buf.is_empty() {
+                return Err(WSError::handshake("connection closed before end of request head"));
+            }
+
+            let start = head.len();
+            head.push_all(buf);
+            match try!(parser.parse(&*head)) {
+                Some((parsed, size)) => { request = Some(parsed); size - start },
+                None => buf.len()
+            }
+        };
+        s.consume(used);
+    }
+    let request = request.unwrap();
+
+    if request.method != "GET" {
+        return Err(WSError::handshake("invalid request method"));
+    }
+
+    // Repeated headers are combined into a comma-separated list
+    let mut headers: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in request.headers.into_iter() {
+        let name = name.to_ascii_lowercase();
+        let combined = match headers.get(&name) {
+            Some(prev) => format!("{}, {}", prev, value),
+            None => value
+        };
+        headers.insert(name, combined);
+    }
+
+    Ok((request.path, headers))
+}
+
+fn header_has_token(headers: &BTreeMap<String, String>, name: &str, token: &str) -> bool {
+    headers.get(name).map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))).unwrap_or(false)
+}
+
+// Replies with error status and builds handshake error with request headers
+fn reject<S: Read + Write>(s: &mut BufStream<S>, status: u16, headers: BTreeMap<String, String>, reason: &'static str) -> WSError {
+    let response = match status {
+        426 => "Upgrade Required\r\nSec-WebSocket-Version: 13",
+        _ => "Bad Request"
+    };
+
+    match write!(s, "HTTP/1.1 {} {}\r\nContent-Length: 0\r\n\r\n", status, response).and_then(|_| s.flush()) {
+        Ok(()) => WSError::Handshake { status: Some(status), headers: headers, reason: reason },
+        Err(e) => WSError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use deflate::{DeflateConfig, PerMessageDeflate};
+    use super::accept_with_options;
+
+    // Sends handshake request with given extension offers, returns server's response head
+    fn handshake(extensions: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            accept_with_options(sock, None, vec![Box::new(PerMessageDeflate::new(DeflateConfig::new()))]).is_ok()
+        });
+
+        let mut sock = TcpStream::connect(addr).unwrap();
+        write!(sock, "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+                      Sec-WebSocket-Extensions: {}\r\n\r\n", extensions).unwrap();
+        assert!(server.join().unwrap());
+
+        // Server's socket is dropped after handshake
+        let mut response = Vec::new();
+        sock.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&*response).into_owned()
+    }
+
+    #[test]
+    fn declined_extension_offer_falls_back() {
+        let response = handshake("permessage-deflate; server_max_window_bits=8, permessage-deflate");
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("\r\nSec-WebSocket-Extensions: permessage-deflate"));
+        assert!(!response.contains("server_max_window_bits=8"));
+    }
+
+    #[test]
+    fn only_declined_extension_offers() {
+        let response = handshake("permessage-deflate; server_max_window_bits=8");
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(!response.contains("Sec-WebSocket-Extensions"));
+    }
+}
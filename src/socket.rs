@@ -1,17 +1,34 @@
 use std::io::{Read, Write, BufRead, BufStream, self};
 use std::mem;
-use std::collections::BTreeMap;
-use std::u16;
-use std::num::{Int, FromPrimitive, ToPrimitive};
-use std::slice::SliceConcatExt;
+use std::time::{Duration, Instant};
 use url::Url;
-use rand::{thread_rng, Rng};
 
 use nonce::Nonce;
-use message::{WSMessage, WSHeader, WS_MASK, WS_LEN, WS_LEN16, WS_LEN64, WS_OPTERM};
-use stream::NetworkStream;
+use message::{WSMessage, WSHeader, WSStatusCode, WS_FIN, WS_OPCODE, WS_OPCTRL, WS_OPCONT, WS_OPTERM, WS_OPTEXT, WS_OPBIN};
+use stream::{NetworkStream, TryClone};
+use proxy::Proxy;
+use tls::TlsConfig;
+use deflate::{DeflateConfig, PerMessageDeflate};
+use error::{WSError, WSResult};
+use extension::{Extension, extension_name, check_conflicts};
+use frame::{self, FrameHeader};
+use codec::FrameCodec;
+use split::{self, WSReader, WSWriter, SplitError};
+use handshake::{self, RequestHeaders, Response, ResponseParser};
 
 
+pub static DEFAULT_MAX_FRAME_SIZE: u64 = 16 << 20;
+pub static DEFAULT_MAX_MESSAGE_SIZE: u64 = 64 << 20;
+pub static DEFAULT_FRAGMENT_SIZE: usize = 64 << 10;
+
+#[derive(Copy, Debug, PartialEq)]
+pub enum WSState {
+    Open,
+    // close frame is sent, waiting for peer's one
+    Closing,
+    Closed
+}
+
 pub struct WebSocket<S = NetworkStream> {
     stream: Option<BufStream<S>>,
     pub url: Url,
@@ -19,213 +36,875 @@ pub struct WebSocket<S = NetworkStream> {
     use_ssl: bool,
     version: u32,
     extensions: Option<Vec<String>>,
-    protocols: Option<Vec<String>>
+    protocols: Option<Vec<String>>,
+    // subprotocol selected by server
+    protocol: Option<String>,
+    // registered extensions to offer, negotiated ones are kept by codec
+    offered: Vec<Box<Extension + Send>>,
+    request: RequestHeaders,
+    response: Option<Response>,
+    codec: FrameCodec,
+    state: WSState,
+    close_status: Option<WSStatusCode>,
+    close_reason: Option<String>,
+    auto_pong: bool,
+    keepalive: Option<Duration>,
+    pong_timeout: Option<Duration>,
+    last_seen: Instant,
+    ping_sent: Option<Instant>,
+    max_redirects: u32,
+    allow_insecure_redirects: bool,
+    proxy: Proxy,
+    tls: TlsConfig
 }
 
 impl WebSocket {
-    pub fn with_options(url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WebSocket {
-        let use_ssl = &*url.scheme == "wss";
+    // TLS settings are used for wss:// only, default ones verify server certificate
+    pub fn with_options(url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>, tls: Option<TlsConfig>) -> WebSocket {
+        let mut ws = WebSocket::configure(None, url, version, protocols, extensions);
+        if let Some(tls) = tls {
+            ws.tls = tls;
+        }
+        ws
+    }
 
-        let port = match url.port() {
-            Some(p) => p,
-            None if use_ssl => 443,
-            _ => 80
-        };
+    #[inline] pub fn new(url: Url) -> WebSocket {
+        WebSocket::with_options(url, 13, None, None, None)
+    }
+
+    // Follow up to `hops` redirects (301, 302, 307, 308) on connect, none by default
+    pub fn set_max_redirects(&mut self, hops: u32) {
+        self.max_redirects = hops;
+    }
+
+    // Follow redirects from wss:// to ws:// (off by default)
+    pub fn set_allow_insecure_redirects(&mut self, enabled: bool) {
+        self.allow_insecure_redirects = enabled;
+    }
+
+    // Proxy to connect through, taken from environment by default
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.proxy = proxy;
+    }
+
+    fn try_connect(&mut self) -> WSResult<()> {
+        self.stream = Some(BufStream::new(try!(NetworkStream::connect_with_options(&*self.hostname, self.use_ssl, &self.proxy, &self.tls))));
+        Ok(())
+    }
+
+    // Connects and performs handshake, following redirects if enabled,
+    // `url` is updated to the final one then
+    pub fn connect(&mut self) -> WSResult<()> {
+        let mut redirects = 0;
+        loop {
+            try!(self.try_connect());
+            let location = match self.handshake() {
+                Ok(()) => break,
+                // Handshake response is the first thing read from TLS stream
+                Err(WSError::Io(e)) => return Err(match self.stream {
+                    Some(ref s) => s.get_ref().read_error(e, &self.tls),
+                    None => WSError::Io(e)
+                }),
+                Err(e) => {
+                    let location = handshake::redirect_location(&e).map(|v| v.to_string());
+                    match location {
+                        Some(location) if redirects < self.max_redirects => location,
+                        _ => return Err(e)
+                    }
+                }
+            };
+
+            redirects += 1;
+            let url = try!(handshake::redirect_url(&self.url, &*location));
+            if self.use_ssl && &*url.scheme == "ws" && !self.allow_insecure_redirects {
+                return Err(WSError::handshake("redirect from wss:// to ws:// refused"));
+            }
+
+            // Credentials are sent to the same origin only (scheme, host and port),
+            // names meant for original host are not sent to another one
+            if !same_origin(&url, &self.url) {
+                self.request = self.request.for_other_host();
+            }
+            if url.serialize_host() != self.url.serialize_host() {
+                self.tls.server_name = None;
+            }
+
+            self.stream = None;
+            self.set_url(url);
+        }
+
+        // Wake up periodically to send keepalive pings
+        let tick = self.keepalive_tick();
+        if let Some(ref s) = self.stream {
+            try!(s.get_ref().set_read_timeout(tick));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    #[inline] pub fn from_stream(stream: S, url: Url) -> WSResult<WebSocket<S>> {
+        WebSocket::from_stream_with_options(stream, url, 13, None, None)
+    }
+
+    // Performs client handshake over already connected transport
+    pub fn from_stream_with_options(stream: S, url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WSResult<WebSocket<S>> {
+        let mut ws = WebSocket::with_stream(stream, url, version, protocols, extensions);
+        try!(ws.handshake());
+        Ok(ws)
+    }
+
+    // Wraps connected transport without handshake, so that it can be configured
+    // (e.g. with `add_extension()`) before calling `handshake()`
+    pub fn with_stream(stream: S, url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WebSocket<S> {
+        WebSocket::configure(Some(BufStream::new(stream)), url, version, protocols, extensions)
+    }
+
+    fn set_url(&mut self, url: Url) {
+        let (hostname, use_ssl) = address(&url);
+        self.hostname = hostname;
+        self.use_ssl = use_ssl;
+        self.url = url;
+    }
+
+    // Extra handshake request headers, must be set before connecting
+    pub fn set_request_headers(&mut self, request: RequestHeaders) {
+        self.request = request;
+    }
+
+    // Registers extension to offer in handshake, must be called before connecting
+    pub fn add_extension(&mut self, ext: Box<Extension + Send>) {
+        self.offered.push(ext);
+    }
+
+    /// Wraps a stream on which opening handshake has already been completed,
+    /// e.g. by `server::accept()`
+    pub fn from_handshaken(stream: BufStream<S>, url: Url, version: u32, protocol: Option<String>, extensions: Vec<Box<Extension + Send>>) -> WebSocket<S> {
+        let mut ws = WebSocket::configure(Some(stream), url, version, None, None);
+        ws.protocol = protocol;
+        ws.codec = FrameCodec::new(true);
+        ws.codec.set_extensions(extensions);
+        ws
+    }
+
+    fn configure(stream: Option<BufStream<S>>, url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WebSocket<S> {
+        let (hostname, use_ssl) = address(&url);
+
+        // Extensions implemented here are handled by their handlers,
+        // the rest is just offered as is
+        let mut offered: Vec<Box<Extension + Send>> = Vec::new();
+        let mut others = Vec::new();
+        for ext in extensions.unwrap_or(&[][..]).iter() {
+            match ext.parse::<DeflateConfig>() {
+                Ok(config) => offered.push(Box::new(PerMessageDeflate::new(config))),
+                Err(_) => others.push(ext.to_string())
+            }
+        }
 
         WebSocket {
-            stream: None,
-            hostname: format!("{}:{}", url.serialize_host().unwrap(), port),
+            stream: stream,
+            hostname: hostname,
             url: url,
             use_ssl: use_ssl,
             version: version,
-            extensions: extensions.map(|v| v.iter().map(|v| v.to_string()).collect()),
-            protocols: protocols.map(|v| v.iter().map(|v| v.to_string()).collect())
+            extensions: if others.is_empty() { None } else { Some(others) },
+            protocols: protocols.map(|v| v.iter().map(|v| v.to_string()).collect()),
+            protocol: None,
+            offered: offered,
+            request: RequestHeaders::new(),
+            response: None,
+            codec: FrameCodec::new(false),
+            state: WSState::Open,
+            close_status: None,
+            close_reason: None,
+            auto_pong: true,
+            keepalive: None,
+            pong_timeout: None,
+            last_seen: Instant::now(),
+            ping_sent: None,
+            max_redirects: 0,
+            allow_insecure_redirects: false,
+            proxy: Proxy::Env,
+            tls: TlsConfig::new()
         }
     }
 
-    #[inline] pub fn new(url: Url) -> WebSocket {
-        WebSocket::with_options(url, 1, None, None)
+    pub fn handshake(&mut self) -> WSResult<()> {
+        let mut nonce = Nonce::new();
+
+        try!(self.write_request(&*nonce));
+
+        nonce = nonce.encode();
+        let response = try!(self.read_response(&*nonce));
+        self.protocol = try!(handshake::check_protocol(&response, self.protocols.as_ref().map(|v| &**v).unwrap_or(&[][..])));
+
+        try!(self.negotiate_extensions(&*response.extensions()));
+        self.response = Some(response);
+        Ok(())
+    }
+
+    // Subprotocol selected by server, if any
+    #[inline] pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|v| &**v)
+    }
+
+    // Handshake response received from server, None on server side
+    // and before handshake is done
+    #[inline] pub fn response(&self) -> Option<&Response> {
+        self.response.as_ref()
     }
 
-    fn try_connect(&mut self) -> io::Result<()> {
-        self.stream = Some(BufStream::new(try!(NetworkStream::connect(&*self.hostname, self.use_ssl))));
+    fn negotiate_extensions(&mut self, accepted: &[&str]) -> WSResult<()> {
+        let mut active = Vec::new();
+        for &ext in accepted.iter() {
+            let name = extension_name(ext);
+            match self.offered.iter().position(|h| h.name() == name) {
+                Some(idx) => {
+                    let mut handler = self.offered.remove(idx);
+                    try!(handler.accept(ext));
+                    active.push(handler);
+                },
+                None => {
+                    // Only offered extensions may be accepted by server
+                    let offered = self.extensions.as_ref().map(|exts| exts.iter().any(|e| extension_name(&**e) == name)).unwrap_or(false);
+                    if !offered {
+                        return Err(WSError::handshake("unexpected extension in Sec-WebSocket-Extensions response header"));
+                    }
+                }
+            }
+        }
+
+        self.offered.clear();
+        try!(check_conflicts(&*active));
+        self.codec.set_extensions(active);
         Ok(())
     }
 
-    fn write_request(&mut self, nonce: &str) -> io::Result<()> {
-        let s = match self.stream { Some(ref mut s) => s, None => return Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None)) };
+    fn write_request(&mut self, nonce: &str) -> WSResult<()> {
+        let mut exts = self.offered.iter().map(|e| e.offer()).collect::<Vec<String>>();
+        if let Some(ref others) = self.extensions {
+            exts.push_all(&**others);
+        }
+
+        let s = match self.stream { Some(ref mut s) => s, None => return Err(WSError::NotConnected) };
+        try!(handshake::write_request(s, &self.url, nonce, self.version, self.protocols.as_ref().map(|v| &**v).unwrap_or(&[][..]), &*exts, &self.request));
 
-        try!(write!(s, "GET {} HTTP/1.1\r\n", self.url.serialize_path().unwrap_or("/".to_string())));
-        try!(write!(s, "Host: {}\r\n", self.url.host().unwrap()));
-        try!(write!(s, "Origin: {}\r\n", self.url.serialize_no_fragment()));
-        try!(write!(s, "Sec-WebSocket-Key: {}\r\n", nonce));
+        Ok(try!(s.flush()))
+    }
 
-        try!(s.write_all(b"Upgrade: websocket\r\n"));
-        try!(s.write_all(b"Connection: Upgrade\r\n"));
-        try!(write!(s, "Sec-WebSocket-Version: {}\r\n", self.version));
-        if let Some(ref protos) = self.protocols {
-            try!(write!(s, "Sec-WebSocket-Protocol: {}\r\n", protos.connect(", ")));
+    fn read_response(&mut self, nonce: &str) -> WSResult<Response> {
+        let s = match self.stream { Some(ref mut s) => s, None => return Err(WSError::NotConnected) };
+
+        // Read response head up to empty line, leaving anything after it buffered
+        let mut parser = ResponseParser::new();
+        let mut head = Vec::new();
+        loop {
+            let (response, used) = {
+                let buf = try!(s.fill_buf());
+                if buf.is_empty() {
+                    return Err(WSError::handshake("connection closed during handshake"));
+                }
+
+                let start = head.len();
+                head.push_all(buf);
+                match try!(parser.parse(&*head)) {
+                    Some((response, size)) => (Some(response), size - start),
+                    None => (None, buf.len())
+                }
+            };
+
+            s.consume(used);
+            if let Some(response) = response {
+                return handshake::check_response(response, nonce);
+            }
         }
-        if let Some(ref exts) = self.extensions {
-            try!(write!(s, "Sec-WebSocket-Extensions: {}\r\n", exts.connect(", ")));
+    }
+
+    // Fills buffer completely, failing if connection is closed before that
+    fn read_bytes(&mut self, buf: &mut [u8]) -> WSResult<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.read(&mut buf[pos..]) {
+                Ok(0) => return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed in the middle of frame", None))),
+                Ok(n) => pos += n,
+                Err(ref e) if self.is_tick(e) => (),
+                Err(e) => return Err(WSError::Io(e))
+            }
         }
-        try!(s.write_all(b"\r\n"));
+        Ok(())
+    }
+
+    #[inline] pub fn state(&self) -> WSState {
+        self.state
+    }
 
-        s.flush()
+    // Status code of peer's close frame (NoCode if it had none),
+    // or Aborted if connection was dropped without closing handshake
+    #[inline] pub fn close_status(&self) -> Option<WSStatusCode> {
+        self.close_status
     }
 
-    fn read_response(&mut self, nonce: &str) -> io::Result<()> {
-        let spaces: &[_] = &[' ', '\t', '\r', '\n'];
-        let s = match self.stream { Some(ref mut s) => s, None => return Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None)) };
-        let mut lines = s.lines();
-        let status = match lines.next() {
-            Some(Ok(line)) => line.splitn(2, ' ').nth(1).and_then(|s| s.parse::<u16>().ok()),
-            Some(Err(e)) => return Err(e),
-            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing response status", None))
+    #[inline] pub fn close_reason(&self) -> Option<&str> {
+        self.close_reason.as_ref().map(|v| &**v)
+    }
+
+    // Reads next frame, echoing peer's close frame if connection is open
+    pub fn read_message(&mut self) -> WSResult<WSMessage> {
+        if self.state == WSState::Closed {
+            return Err(WSError::Closed);
+        }
+
+        let res = self.wait_frame().and_then(|_| self.read_frame());
+        let msg = try!(self.check(res));
+        try!(self.process(&msg));
+        Ok(msg)
+    }
+
+    // Reads next data message as a stream, unmasking and joining fragments on the fly,
+    // so that payload never has to be fully loaded into memory.
+    // Control frames received meanwhile are handled as by read_message() and skipped.
+    // Payload transformed by extensions (e.g. permessage-deflate) is decoded chunk
+    // by chunk, frames of extensions which can't do that are buffered one by one.
+    // Reader must be read to the end or `skip()`ped, dropping it in the middle
+    // of message closes connection.
+    pub fn read_stream(&mut self) -> WSResult<WSMessageReader<S>> {
+        if self.state == WSState::Closed {
+            return Err(WSError::Closed);
+        }
+
+        let mut reader = WSMessageReader {
+            sock: self,
+            opcode: WSHeader::empty(),
+            frame: None,
+            buffer: Vec::new(),
+            pos: 0,
+            fin: false
         };
+        match reader.next_frame() {
+            Ok(()) => Ok(reader),
+            Err(e) => {
+                // nothing to skip on drop
+                reader.fin = true;
+                Err(e)
+            }
+        }
+    }
 
-        match status {
-            Some(101) => (),
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid response status", None))
+    // Handles failures: connection is either aborted or failed with error's close status
+    fn check<T>(&mut self, res: WSResult<T>) -> WSResult<T> {
+        match res {
+            Err(WSError::Io(e)) => {
+                // Connection dropped without closing handshake
+                self.state = WSState::Closed;
+                self.close_status = Some(WSStatusCode::Aborted);
+                Err(WSError::Io(e))
+            },
+            Err(e) => {
+                if let Some(status) = e.status() {
+                    self.fail(status);
+                }
+                Err(e)
+            },
+            ok => ok
         }
+    }
 
-        let headers = lines.map(|r| r.unwrap_or("\r\n".to_string())) .take_while(|l| &**l != "\r\n")
-            .map(|s| s.splitn(1, ':').map(|s| s.trim_matches(spaces).to_string()).collect::<Vec<String>>())
-            .map(|p| (p[0].to_string(), p[1].to_string()))
-            .collect::<BTreeMap<String, String>>();
+    // Keeps connection state up to date with received frame
+    fn process(&mut self, msg: &WSMessage) -> WSResult<()> {
+        self.last_seen = Instant::now();
 
-        try!(s.flush());
+        if msg.is_pong() {
+            self.ping_sent = None;
+        }
+
+        if msg.is_ping() && self.auto_pong && self.state == WSState::Open {
+            let pong = self.automask(WSMessage::pong(&*msg.data));
+            try!(self.write_frame(&pong));
+        }
+
+        if msg.is_close() {
+            self.close_status = Some(msg.status.unwrap_or(WSStatusCode::NoCode));
+            self.close_reason = Some(msg.to_string());
 
-        let response = headers.get("Sec-WebSocket-Accept");
-        match response {
-            Some(r) if nonce == *r => (),
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing Sec-WebSocket-Accept header in response", None))
+            if self.state == WSState::Open {
+                self.state = WSState::Closing;
+                let reply = self.automask(WSMessage { header: WS_FIN | WS_OPTERM, data: Vec::new(), status: msg.status });
+                try!(self.write_frame(&reply));
+            }
+            self.state = WSState::Closed;
         }
 
         Ok(())
     }
 
-    pub fn connect(&mut self) -> io::Result<()> {
-        let mut nonce = Nonce::new();
+    // Sends frame, no data may be sent after close frame
+    pub fn send_message(&mut self, msg: &WSMessage) -> WSResult<()> {
+        if self.state != WSState::Open {
+            return Err(WSError::Closed);
+        }
 
-        try!(self.try_connect());
-        try!(self.write_request(&*nonce));
+        try!(self.write_frame(msg));
+        if msg.is_close() {
+            self.state = WSState::Closing;
+        }
+        Ok(())
+    }
 
-        nonce = nonce.encode();
-        try!(self.read_response(&*nonce));
+    // Starts data message (WS_OPTEXT, WS_OPBIN or extension's data opcode) to be
+    // written as a stream, it is sent in fragments as writer's buffer fills up,
+    // until `finish()` is called. Control frames can't be fragmented. Dropping
+    // writer without `finish()` after some data is sent closes connection.
+    pub fn begin_message(&mut self, opcode: WSHeader) -> WSResult<WSMessageWriter<S>> {
+        let opcode = opcode & WS_OPCODE;
+        if opcode.contains(WS_OPCTRL) || opcode == WS_OPCONT {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "only data messages can be streamed", None)));
+        }
+
+        if self.state != WSState::Open {
+            return Err(WSError::Closed);
+        }
+
+        Ok(WSMessageWriter {
+            sock: self,
+            opcode: opcode,
+            buffer: Vec::with_capacity(DEFAULT_FRAGMENT_SIZE),
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            started: false,
+            finished: false
+        })
+    }
 
+    // Starts closing handshake and waits for peer's close frame,
+    // data messages received meanwhile are discarded
+    pub fn close(&mut self, status: WSStatusCode, reason: &str) -> WSResult<()> {
+        if self.state == WSState::Open {
+            let msg = self.automask(WSMessage::close(status, reason.as_bytes()));
+            try!(self.send_message(&msg));
+        }
+
+        while self.state != WSState::Closed {
+            try!(self.read_message());
+        }
         Ok(())
     }
 
-    fn read_header(&mut self) -> io::Result<WSHeader> {
-        let h: u16;
-        try!(self.read(mem::transmute(h)));
-        Ok(WSHeader::from_bits_truncate(h.to_le()))
+    // Fails connection after peer's misbehavior: sends close frame
+    // with given status and drops connection without waiting for reply
+    fn fail(&mut self, status: WSStatusCode) {
+        if self.state == WSState::Open {
+            let msg = self.automask(WSMessage::close(status, b""));
+            let _ = self.write_frame(&msg);
+        }
+        self.stream = None;
+        self.state = WSState::Closed;
+        self.close_status = Some(status);
     }
 
-    fn read_length(&mut self, header: &WSHeader) -> io::Result<u64> {
-        let wslen = *header & WS_LEN;
-        if wslen == WS_LEN16 { self.read_be_u16().map(|v| v as u64) }
-        else if wslen == WS_LEN64 { self.read_be_u64() }
-        else { Ok(wslen.bits() as u64) }
+    // Validate incoming frames strictly as per RFC 6455 (on by default),
+    // violations fail connection with ProtocolError or InvalidData status
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.codec.set_strict(enabled);
     }
 
-    pub fn read_message(&mut self) -> io::Result<WSMessage> {
-        let header = try!(self.read_header());
-        let mut len = try!(self.read_length(&header));
+    // Limit frame payload size (16 MiB by default), None to disable
+    pub fn set_max_frame_size(&mut self, limit: Option<u64>) {
+        self.codec.set_max_frame_size(limit);
+    }
 
-        let mask = if header.contains(WS_MASK) {
-            Some(try!(self.read_be_u32()))
-        } else {
-            None
-        };
+    // Limit size of whole (possibly fragmented) message payload (64 MiB by default),
+    // None to disable
+    pub fn set_max_message_size(&mut self, limit: Option<u64>) {
+        self.codec.set_max_message_size(limit);
+    }
 
-        // If this is the terminating frame (close command),
-        // first two bytes of data MUST BE u16 status code
-        let mut status = if header.contains(WS_OPTERM) {
-            // compensate length of status code
-            len = len - 2;
-            Some(try!(self.read_be_u16()))
-        } else {
-            None
-        };
+    // Reply to pings with pongs automatically (on by default)
+    pub fn set_auto_pong(&mut self, enabled: bool) {
+        self.auto_pong = enabled;
+    }
 
-        let mut data = try!(self.read_exact(len as usize));
+    // Send ping every `interval` of inactivity, and abort connection if pong
+    // doesn't arrive within `pong_timeout` (defaults to `interval`).
+    // Must be set before `connect()`; for streams passed to `from_stream()`
+    // read timeout must be set on the stream by caller, so that reads wake up.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, pong_timeout: Option<Duration>) {
+        self.keepalive = interval;
+        self.pong_timeout = pong_timeout;
+    }
 
-        // If we have mask, decrypt data
-        if let Some(mut m) = mask {
-            // decrypt status if present
-            if let Some(s) = status {
-                status = Some(s ^ (m & 0xffff) as u16);
-                // compensate the usage of two mask bytes
-                m = m.rotate_right(16);
+    fn keepalive_tick(&self) -> Option<Duration> {
+        match (self.keepalive, self.pong_timeout) {
+            (Some(i), Some(t)) => Some(if i < t { i } else { t }),
+            (i, _) => i
+        }
+    }
+
+    // Waits for the next frame to arrive, sending keepalive pings meanwhile
+    fn wait_frame(&mut self) -> WSResult<()> {
+        loop {
+            try!(self.check_keepalive());
+
+            let res = match self.stream {
+                Some(ref mut s) => s.fill_buf().map(|_| ()),
+                None => return Err(WSError::NotConnected)
+            };
+
+            match res {
+                Ok(()) => return Ok(()),
+                Err(ref e) if self.is_tick(e) => (),
+                Err(e) => return Err(WSError::Io(e))
             }
-            data = WebSocket::mask_data(&*data, m);
         }
+    }
 
-        Ok(WSMessage { header: header, data: data, status: status.and_then(FromPrimitive::from_u16) })
+    // Read timeout set for keepalive only wakes up reads, it's not a failure,
+    // and is retried in the middle of frame
+    fn is_tick(&self, err: &io::Error) -> bool {
+        self.keepalive.is_some() && (err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut)
     }
 
-    fn mask_data(data: &[u8], mask: u32) -> Vec<u8> {
-        data.iter().enumerate().map(|(i, b)| *b ^ (mask >> ((i % 4) << 3) & 0xff) as u8).collect::<Vec<u8>>()
+    fn check_keepalive(&mut self) -> WSResult<()> {
+        let interval = match self.keepalive {
+            Some(i) => i,
+            None => return Ok(())
+        };
+
+        match self.ping_sent {
+            Some(sent) if sent.elapsed() >= self.pong_timeout.unwrap_or(interval) => {
+                // Dead peer, drop connection without closing handshake
+                self.stream = None;
+                self.state = WSState::Closed;
+                self.close_status = Some(WSStatusCode::Aborted);
+                Err(WSError::Io(io::Error::new(io::ErrorKind::TimedOut, "no pong received in time", None)))
+            },
+            None if self.state == WSState::Open && self.last_seen.elapsed() >= interval => {
+                let ping = self.automask(WSMessage::ping(b"keepalive"));
+                try!(self.write_frame(&ping));
+                self.ping_sent = Some(Instant::now());
+                Ok(())
+            },
+            _ => Ok(())
+        }
     }
 
-    pub fn send_message(&mut self, msg: &WSMessage) -> io::Result<()> {
-        let mut len = msg.data.len() as u64;
-        let mut hdr = msg.header - WS_LEN;
+    // Client must mask all frames it sends, server must not
+    fn automask(&self, msg: WSMessage) -> WSMessage {
+        if self.codec.is_server() { msg.unmask() } else { msg.mask() }
+    }
 
-        // If we have status set, the data length is increased by status size
-        if msg.status.is_some() {
-            len = len + 2;
+    #[inline] fn read_frame(&mut self) -> WSResult<WSMessage> {
+        let frame = try!(self.read_frame_header());
+        self.read_frame_payload(frame)
+    }
+
+    // Reads and validates frame header up to payload
+    fn read_frame_header(&mut self) -> WSResult<FrameHeader> {
+        let mut buf = [0u8; 14];
+        try!(self.read_bytes(&mut buf[..2]));
+        let size = frame::header_size(&[buf[0], buf[1]]);
+        try!(self.read_bytes(&mut buf[2..size]));
+
+        Ok(try!(self.codec.decode_header(&buf[..size])).unwrap())
+    }
+
+    fn read_frame_payload(&mut self, frame: FrameHeader) -> WSResult<WSMessage> {
+        let mut payload = vec![0u8; frame.len as usize];
+        try!(self.read_bytes(&mut *payload));
+        self.codec.decode_payload(&frame, payload)
+    }
+
+    fn write_frame(&mut self, msg: &WSMessage) -> WSResult<()> {
+        let mut buf = Vec::with_capacity(msg.data.len() + 16);
+        try!(self.codec.encode(msg, &mut buf));
+        try!(self.write_all(&*buf));
+
+        Ok(try!(self.flush()))
+    }
+
+    pub fn iter(&mut self) -> WSMessages<S> {
+        WSMessages { sock: self }
+    }
+}
+
+impl<S: Read + Write + TryClone> WebSocket<S> {
+    // Splits connection into reading and writing halves which can be moved
+    // to different threads. Reader answers pings and close frames through writer;
+    // keepalive pings are not sent by split connection, and reader waits for
+    // frames indefinitely then. Only plain TCP connections can be split: wss://
+    // ones fail with error, as TLS session can't be shared by two stream handles.
+    // WebSocket is returned back in error, so that it can be used as is.
+    pub fn split(mut self) -> Result<(WSReader<S>, WSWriter<S>), SplitError<S>> {
+        let res = match self.stream {
+            Some(ref mut s) => s.flush().and_then(|_| s.get_ref().try_clone()).map_err(WSError::Io),
+            None => Err(WSError::NotConnected)
+        };
+        let writer = match res {
+            Ok(writer) => writer,
+            Err(e) => return Err(SplitError::new(e, self))
+        };
+
+        let stream = self.stream.take().unwrap();
+        let keepalive = self.keepalive.is_some();
+        Ok(split::split(stream, writer, self.codec, self.state, self.close_status, self.close_reason, self.auto_pong, keepalive))
+    }
+}
+
+// Host and port to connect to, and whether TLS is used
+fn address(url: &Url) -> (String, bool) {
+    let use_ssl = &*url.scheme == "wss";
+
+    let port = match url.port() {
+        Some(p) => p,
+        None if use_ssl => 443,
+        _ => 80
+    };
+
+    (format!("{}:{}", url.serialize_host().unwrap(), port), use_ssl)
+}
+
+// Whether URLs have the same scheme, host and port (default one if omitted)
+fn same_origin(a: &Url, b: &Url) -> bool {
+    address(a) == address(b)
+}
+
+// Payload position of frame being streamed
+struct StreamFrame {
+    frame: FrameHeader,
+    offset: u64
+}
+
+pub struct WSMessageReader<'a, S: 'a = NetworkStream> {
+    sock: &'a mut WebSocket<S>,
+    opcode: WSHeader,
+    frame: Option<StreamFrame>,
+    // whole frame, when it was transformed by extensions
+    buffer: Vec<u8>,
+    pos: usize,
+    fin: bool
+}
+
+impl<'a, S: Read + Write> WSMessageReader<'a, S> {
+    // Message opcode, WS_OPTEXT or WS_OPBIN (or extension's one)
+    #[inline] pub fn opcode(&self) -> WSHeader {
+        self.opcode
+    }
+
+    #[inline] pub fn is_text(&self) -> bool { self.opcode == WS_OPTEXT }
+    #[inline] pub fn is_binary(&self) -> bool { self.opcode == WS_OPBIN }
+
+    // Reads next data frame of message, handling control frames in between
+    fn next_frame(&mut self) -> WSResult<()> {
+        loop {
+            let res = self.sock.wait_frame().and_then(|_| self.sock.read_frame_header());
+            let frame = try!(self.sock.check(res));
+
+            if frame.header.contains(WS_OPCTRL) || !self.sock.codec.can_stream() {
+                let fin = frame.header.contains(WS_FIN);
+                let res = self.sock.read_frame_payload(frame);
+                let msg = try!(self.sock.check(res));
+                try!(self.sock.process(&msg));
+
+                if msg.is_close() {
+                    return Err(WSError::Closed);
+                } else if !msg.is_control() {
+                    self.start(&msg.header, fin);
+                    self.buffer = msg.data;
+                    self.pos = 0;
+                    return Ok(());
+                }
+            } else {
+                let res = self.sock.codec.start_stream(&frame);
+                try!(self.sock.check(res));
+                self.sock.last_seen = Instant::now();
+
+                self.start(&frame.header, frame.header.contains(WS_FIN));
+                self.frame = Some(StreamFrame { frame: frame, offset: 0 });
+                return Ok(());
+            }
         }
+    }
 
-        // Encode and send length along with header
-        if len < WS_LEN16.bits() as u64 {
-            hdr = hdr | WSHeader::from_bits_truncate(len as u16 & WS_LEN.bits());
-            try!(self.write_all(mem::transmute(hdr.bits().to_be())));
+    fn start(&mut self, header: &WSHeader, fin: bool) {
+        if *header & WS_OPCODE != WS_OPCONT {
+            self.opcode = *header & WS_OPCODE;
+        }
+        self.fin = fin;
+    }
 
-        } else if len < u16::MAX as u64 {
-            hdr = hdr | WS_LEN16;
-            try!(self.write_all(mem::transmute(hdr.bits().to_be())));
-            try!(self.write_all(mem::transmute(len as u16)));
+    // Reads from current frame being streamed. Payload transformed by extensions
+    // is decoded into buffer, and nothing is read into `buf` then.
+    fn read_frame(&mut self, buf: &mut [u8]) -> WSResult<usize> {
+        let transform = !self.sock.codec.extensions().is_empty();
+        let mut raw = if transform { vec![0u8; if buf.len() < DEFAULT_FRAGMENT_SIZE { buf.len() } else { DEFAULT_FRAGMENT_SIZE }] } else { Vec::new() };
 
-        } else {
-            hdr = hdr | WS_LEN64;
-            try!(self.write_all(mem::transmute(hdr.bits().to_be())));
-            try!(self.write_all(mem::transmute((len as u64).to_be())));
-        }
-
-        // If user required masking, encrypt all data
-        if hdr.contains(WS_MASK) {
-            // Generate and send random mask
-            let mut mask = thread_rng().gen::<u32>();
-            try!(self.write_all(mem::transmute(mask.to_be())));
-
-            // Encrypt status code if present
-            if let Some(status) = msg.status {
-                try!(self.write_all(mem::transmute((status.to_u16().unwrap() ^ (mask & 0xffff) as u16).to_be())));
-                // compensate for mask already used for status encryption
-                mask = mask.rotate_right(16);
+        let (n, remaining, frame, offset) = {
+            let into = if transform { &mut *raw } else { &mut *buf };
+            let stream = match self.frame { Some(ref mut f) => f, None => return Ok(0) };
+            let remaining = stream.frame.len - stream.offset;
+            let want = if (into.len() as u64) < remaining { into.len() } else { remaining as usize };
+
+            // Empty frame (or buffer) has nothing to read, and reading into empty
+            // buffer may block on the stream till more data comes
+            let mut read = if want == 0 { Some(0) } else { None };
+            while read.is_none() {
+                let res = match self.sock.stream {
+                    Some(ref mut s) => s.read(&mut into[..want]),
+                    None => return Err(WSError::NotConnected)
+                };
+                match res {
+                    Ok(n) => read = Some(n),
+                    Err(ref e) if self.sock.is_tick(e) => (),
+                    Err(e) => return Err(WSError::Io(e))
+                }
             }
+            let n = read.unwrap();
+            if n == 0 && want > 0 {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed in the middle of frame", None)));
+            }
+            let offset = stream.offset;
+            stream.offset += n as u64;
+            (n, remaining - n as u64, stream.frame, offset)
+        };
 
-            try!(self.write_all(&*WebSocket::mask_data(&*msg.data, mask)));
+        if n > 0 {
+            self.sock.last_seen = Instant::now();
+        }
+
+        if remaining == 0 {
+            self.frame = None;
+        }
+
+        if transform {
+            self.buffer.clear();
+            self.pos = 0;
+            try!(self.sock.codec.stream_decode(&frame, &mut raw[..n], offset, &mut self.buffer));
+            Ok(0)
         } else {
-            // Send status code if present
-            if let Some(status) = msg.status {
-                try!(self.write_all(mem::transmute(status.to_u16().unwrap().to_be())));
+            try!(self.sock.codec.stream_payload(&frame, &mut buf[..n], offset));
+            Ok(n)
+        }
+    }
+
+    // Whether all frames of message are read
+    #[inline] fn is_done(&self) -> bool {
+        self.fin && self.frame.is_none()
+    }
+
+    // Discards the rest of message, so that next one can be read
+    pub fn skip(mut self) -> WSResult<()> {
+        let mut buf = [0u8; 4096];
+        while try!(self.read(&mut buf)) > 0 {}
+        Ok(())
+    }
+}
+
+impl<'a, S: Read + Write> Read for WSMessageReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buffer.len() {
+                let n = try!((&self.buffer[self.pos..]).read(buf));
+                self.pos += n;
+                return Ok(n);
             }
-            try!(self.write_all(&*msg.data));
+
+            if self.frame.is_some() {
+                let res = self.read_frame(buf);
+                let n = try!(self.sock.check(res));
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                continue;
+            }
+
+            if self.fin {
+                return Ok(0);
+            }
+
+            try!(self.next_frame());
+        }
+    }
+}
+
+pub struct WSMessageWriter<'a, S: 'a = NetworkStream> {
+    sock: &'a mut WebSocket<S>,
+    opcode: WSHeader,
+    buffer: Vec<u8>,
+    fragment_size: usize,
+    // first frame is sent
+    started: bool,
+    finished: bool
+}
+
+impl<'a, S: Read + Write> WSMessageWriter<'a, S> {
+    // Payload size of each fragment (64 KiB by default)
+    pub fn fragment_size(mut self, size: usize) -> WSMessageWriter<'a, S> {
+        self.fragment_size = if size > 0 { size } else { 1 };
+        let additional = self.fragment_size.saturating_sub(self.buffer.len());
+        self.buffer.reserve(additional);
+        self
+    }
+
+    // Sends final frame with the rest of buffered data
+    pub fn finish(mut self) -> WSResult<()> {
+        self.finished = true;
+        self.send_frame(true)
+    }
+
+    fn send_frame(&mut self, fin: bool) -> WSResult<()> {
+        let mut header = if self.started { WS_OPCONT } else { self.opcode };
+        if fin {
+            header = header | WS_FIN;
+        }
+
+        let data = mem::replace(&mut self.buffer, Vec::with_capacity(self.fragment_size));
+        let frame = self.sock.automask(WSMessage { header: header, data: data, status: None });
+        self.started = true;
+
+        let res = self.sock.write_frame(&frame);
+        self.sock.check(res)
+    }
+}
+
+impl<'a, S: Read + Write> Write for WSMessageWriter<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() >= self.fragment_size {
+            try!(self.send_frame(false));
         }
 
-        self.flush()
+        let n = if buf.len() < self.fragment_size - self.buffer.len() { buf.len() } else { self.fragment_size - self.buffer.len() };
+        self.buffer.push_all(&buf[..n]);
+        Ok(n)
     }
 
-    pub fn iter(&mut self) -> WSMessages {
-        WSMessages { sock: self }
+    // Sends buffered data as a fragment
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            try!(self.send_frame(false));
+        }
+        Ok(())
     }
 }
 
-impl Read for WebSocket {
+// Message not finished explicitly (e.g. after failed `io::copy()`) must not look
+// complete to peer, so connection is closed if any part of it is already sent
+impl<'a, S: Read + Write> Drop for WSMessageWriter<'a, S> {
+    fn drop(&mut self) {
+        if !self.finished && self.started && self.sock.state != WSState::Closed {
+            self.sock.fail(WSStatusCode::GoneAway);
+        }
+    }
+}
+
+// Connection can't be used after message is left in the middle, and the rest
+// of it may be too large to skip, so connection is closed then
+impl<'a, S: Read + Write> Drop for WSMessageReader<'a, S> {
+    fn drop(&mut self) {
+        if !self.is_done() && self.sock.state != WSState::Closed {
+            self.sock.fail(WSStatusCode::GoneAway);
+        }
+    }
+}
+
+impl<S: Read + Write> Read for WebSocket<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self.stream {
             Some(ref mut s) => s.read(buf),
@@ -234,7 +913,7 @@ impl Read for WebSocket {
     }
 }
 
-impl Write for WebSocket {
+impl<S: Read + Write> Write for WebSocket<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self.stream {
             Some(ref mut s) => s.write(buf),
@@ -250,7 +929,7 @@ impl Write for WebSocket {
     }
 }
 
-impl BufRead for WebSocket {
+impl<S: Read + Write> BufRead for WebSocket<S> {
     fn fill_buf<'a>(&'a mut self) -> io::Result<&'a [u8]> {
         match self.stream {
             Some(ref mut s) => s.fill_buf(),
@@ -266,29 +945,29 @@ impl BufRead for WebSocket {
     }
 }
 
-pub struct WSMessages<'a> {
-    sock: &'a mut WebSocket
+pub struct WSMessages<'a, S: 'a = NetworkStream> {
+    sock: &'a mut WebSocket<S>
 }
 
-pub struct WSDefragMessages<'a> {
-    underlying: &'a mut WSMessages<'a>,
+pub struct WSDefragMessages<'a, S: 'a = NetworkStream> {
+    underlying: &'a mut WSMessages<'a, S>,
     buffer: WSMessage
 }
 
-impl<'a> WSMessages<'a> {
-    pub fn defrag(&'a mut self) -> WSDefragMessages<'a> {
+impl<'a, S: Read + Write> WSMessages<'a, S> {
+    pub fn defrag(&'a mut self) -> WSDefragMessages<'a, S> {
         WSDefragMessages{ underlying: self, buffer: WSMessage{ header: WSHeader::empty(), data: Vec::new(), status: None } }
     }
 }
 
-impl<'a> Iterator for WSMessages<'a> {
+impl<'a, S: Read + Write> Iterator for WSMessages<'a, S> {
     type Item = WSMessage;
     fn next(&mut self) -> Option<WSMessage> {
         self.sock.read_message().ok()
     }
 }
 
-impl<'a> WSDefragMessages<'a> {
+impl<'a, S: Read + Write> WSDefragMessages<'a, S> {
     fn popbuf(&mut self) -> Option<WSMessage> {
         if self.buffer.data.is_empty() {
             None
@@ -304,7 +983,7 @@ impl<'a> WSDefragMessages<'a> {
     }
 }
 
-impl<'a> Iterator for WSDefragMessages<'a> {
+impl<'a, S: Read + Write> Iterator for WSDefragMessages<'a, S> {
     type Item = WSMessage;
     fn next(&mut self) -> Option<WSMessage> {
         loop {
@@ -315,11 +994,20 @@ impl<'a> Iterator for WSDefragMessages<'a> {
                         return Some(msg);
                     } else if msg.is_first() {
                         self.swapbuf(&mut msg);
-                    } else if msg.is_more() {
-                        self.buffer.push(msg);
-                    } else if msg.is_last() {
-                        self.buffer.push(msg);
-                        return self.popbuf().map(|v| v.last());
+                    } else {
+                        // Socket checks message size itself, unless limit is changed in the middle of message
+                        let size = (self.buffer.data.len() + msg.data.len()) as u64;
+                        if self.underlying.sock.codec.check_message_size(size).is_err() {
+                            self.underlying.sock.fail(WSStatusCode::TooLargeData);
+                            return None;
+                        }
+
+                        if msg.is_more() {
+                            self.buffer.push(msg);
+                        } else if msg.is_last() {
+                            self.buffer.push(msg);
+                            return self.popbuf().map(|v| v.last());
+                        }
                     }
                 }
             }
@@ -327,3 +1015,176 @@ impl<'a> Iterator for WSDefragMessages<'a> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write, self};
+    use std::num::ToPrimitive;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use url::Url;
+    use message::{WSMessage, WS_OPBIN};
+    use stream::TryClone;
+    use proxy::Proxy;
+    use handshake::RequestHeaders;
+    use super::{WebSocket, WSState, same_origin};
+
+    // Stream with given input, which fails reads past its end
+    // instead of blocking as socket would
+    struct Mock {
+        input: Vec<u8>,
+        pos: usize,
+        output: Vec<u8>
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.input.len() {
+                return Err(io::Error::new(io::ErrorKind::Other, "read past the end of input", None));
+            }
+            let n = try!((&self.input[self.pos..]).read(buf));
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.push_all(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TryClone for Mock {
+        fn try_clone(&self) -> io::Result<Mock> {
+            Err(io::Error::new(io::ErrorKind::Other, "mock can't be cloned", None))
+        }
+    }
+
+    fn client(input: &[u8]) -> WebSocket<Mock> {
+        let mock = Mock { input: input.to_vec(), pos: 0, output: Vec::new() };
+        WebSocket::with_stream(mock, Url::parse("ws://localhost/").unwrap(), 13, None, None)
+    }
+
+    #[test]
+    fn read_stream_empty_message() {
+        let mut ws = client(b"\x81\x00");
+        let mut reader = ws.read_stream().unwrap();
+        assert!(reader.is_text());
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn read_stream_empty_final_frame() {
+        // as sent by WSMessageWriter::finish() after flush()
+        let mut ws = client(b"\x01\x02Hi\x80\x00");
+        let mut reader = ws.read_stream().unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"Hi".to_vec());
+    }
+
+    #[test]
+    fn unfinished_message_closes_connection() {
+        let mut ws = client(b"");
+        {
+            let mut writer = ws.begin_message(WS_OPBIN).unwrap().fragment_size(2);
+            writer.write_all(b"abc").unwrap();
+        }
+        assert_eq!(ws.state(), WSState::Closed);
+        assert_eq!(ws.close_status().and_then(|s| s.to_u16()), Some(1001));
+    }
+
+    #[test]
+    fn unstarted_message_keeps_connection() {
+        let mut ws = client(b"");
+        {
+            let mut writer = ws.begin_message(WS_OPBIN).unwrap();
+            writer.write_all(b"abc").unwrap();
+        }
+        assert_eq!(ws.state(), WSState::Open);
+        assert!(ws.send_message(&WSMessage::binary(b"next").mask()).is_ok());
+    }
+
+    #[test]
+    fn failed_split_returns_connection() {
+        let err = client(b"\x81\x02Hi").split().err().unwrap();
+        let mut ws = err.into_inner();
+        assert_eq!(ws.state(), WSState::Open);
+        assert_eq!(ws.read_message().unwrap().data, b"Hi".to_vec());
+    }
+
+    #[test]
+    fn idle_split_reader_waits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            // several keepalive intervals pass before frame is sent
+            thread::sleep(Duration::from_millis(200));
+            sock.write_all(b"\x81\x02Hi").unwrap();
+        });
+
+        let sock = TcpStream::connect(addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut ws = WebSocket::with_stream(sock, Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        ws.set_keepalive(Some(Duration::from_millis(50)), None);
+
+        let (mut reader, _writer) = ws.split().unwrap();
+        assert_eq!(reader.read_message().unwrap().data, b"Hi".to_vec());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn origin_includes_scheme_and_port() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(same_origin(&url("ws://example.com/a"), &url("ws://example.com:80/b")));
+        assert!(same_origin(&url("wss://example.com/"), &url("wss://example.com:443/")));
+        assert!(!same_origin(&url("ws://example.com/"), &url("ws://example.com:8080/")));
+        assert!(!same_origin(&url("wss://example.com/"), &url("ws://example.com:443/")));
+        assert!(!same_origin(&url("ws://example.com/"), &url("ws://example.org/")));
+    }
+
+    // Accepts connection and returns request head, running `reply` then
+    fn fake_server<F>(listener: TcpListener, reply: F) -> thread::JoinHandle<String> where F: FnOnce(&mut TcpStream) + Send + 'static {
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(&b"\r\n\r\n"[..]) && sock.read(&mut byte).unwrap() == 1 {
+                head.push(byte[0]);
+            }
+            reply(&mut sock);
+            String::from_utf8(head).unwrap()
+        })
+    }
+
+    #[test]
+    fn redirect_to_other_port_drops_credentials() {
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = target.local_addr().unwrap().port();
+        // handshake fails after redirect, as target closes connection
+        let target = fake_server(target, |_| ());
+
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&*format!("ws://127.0.0.1:{}/", origin.local_addr().unwrap().port())).unwrap();
+        let origin = fake_server(origin, move |sock| {
+            write!(sock, "HTTP/1.1 302 Found\r\nLocation: ws://127.0.0.1:{}/\r\nContent-Length: 0\r\n\r\n", port).unwrap();
+        });
+
+        let mut ws = WebSocket::new(url);
+        ws.set_proxy(Proxy::Direct);
+        ws.set_max_redirects(1);
+        ws.set_request_headers(RequestHeaders::new().header("Authorization", "Bearer secret"));
+        assert!(ws.connect().is_err());
+
+        assert!(origin.join().unwrap().contains("\r\nAuthorization: Bearer secret\r\n"));
+        assert!(!target.join().unwrap().contains("Authorization"));
+    }
+}
@@ -0,0 +1,282 @@
+use std::io::{Read, Write, BufStream, self};
+use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::error::Error;
+
+use message::{WSMessage, WSStatusCode, WS_FIN, WS_OPTERM};
+use stream::NetworkStream;
+use error::{WSError, WSResult};
+use socket::{WebSocket, WSState};
+use codec::FrameCodec;
+use frame::{self, FrameHeader};
+
+// Connection state both halves of split WebSocket see, along with
+// writing end of the stream. Reader locks it only to decode frames
+// and to reply to peer, never while waiting for data.
+struct Shared<S> {
+    stream: Option<S>,
+    codec: FrameCodec,
+    state: WSState,
+    close_status: Option<WSStatusCode>,
+    close_reason: Option<String>
+}
+
+impl<S: Write> Shared<S> {
+    fn automask(&self, msg: WSMessage) -> WSMessage {
+        if self.codec.is_server() { msg.unmask() } else { msg.mask() }
+    }
+
+    fn write_frame(&mut self, msg: &WSMessage) -> WSResult<()> {
+        let stream = match self.stream {
+            Some(ref mut s) => s,
+            None => return Err(WSError::NotConnected)
+        };
+
+        let mut buf = Vec::with_capacity(msg.data.len() + 16);
+        try!(self.codec.encode(msg, &mut buf));
+        try!(stream.write_all(&*buf));
+
+        Ok(try!(stream.flush()))
+    }
+
+    fn fail(&mut self, status: WSStatusCode) {
+        if self.state == WSState::Open {
+            let msg = self.automask(WSMessage::close(status, b""));
+            let _ = self.write_frame(&msg);
+        }
+        self.stream = None;
+        self.state = WSState::Closed;
+        self.close_status = Some(status);
+    }
+}
+
+// Reading half of split WebSocket, pings and close frames it receives
+// are answered through the writing half
+pub struct WSReader<S = NetworkStream> {
+    stream: Option<BufStream<S>>,
+    shared: Arc<Mutex<Shared<S>>>,
+    auto_pong: bool,
+    // read timeout set for keepalive is left on the stream,
+    // its expiry is not an error then
+    keepalive: bool
+}
+
+// Writing half of split WebSocket, may be cloned to send from several threads
+pub struct WSWriter<S = NetworkStream> {
+    shared: Arc<Mutex<Shared<S>>>
+}
+
+// Error of `WebSocket::split()`, connection is left intact and can be taken back
+pub struct SplitError<S> {
+    error: WSError,
+    socket: WebSocket<S>
+}
+
+impl<S> SplitError<S> {
+    #[inline] pub fn new(error: WSError, socket: WebSocket<S>) -> SplitError<S> {
+        SplitError { error: error, socket: socket }
+    }
+
+    #[inline] pub fn error(&self) -> &WSError {
+        &self.error
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket<S> {
+        self.socket
+    }
+}
+
+impl<S> fmt::Debug for SplitError<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SplitError({:?})", self.error)
+    }
+}
+
+impl<S> fmt::Display for SplitError<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can't split connection: {}", self.error)
+    }
+}
+
+impl<S> Error for SplitError<S> {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        Some(&self.error as &Error)
+    }
+}
+
+// Builds halves from connection parts, `reader` is the original stream with any
+// data buffered so far, `writer` is another handle to the same connection
+pub fn split<S: Read + Write>(reader: BufStream<S>, writer: S, codec: FrameCodec, state: WSState,
+                              close_status: Option<WSStatusCode>, close_reason: Option<String>,
+                              auto_pong: bool, keepalive: bool) -> (WSReader<S>, WSWriter<S>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        stream: Some(writer),
+        codec: codec,
+        state: state,
+        close_status: close_status,
+        close_reason: close_reason
+    }));
+
+    (WSReader { stream: Some(reader), shared: shared.clone(), auto_pong: auto_pong, keepalive: keepalive }, WSWriter { shared: shared })
+}
+
+impl<S: Read + Write> WSReader<S> {
+    #[inline] pub fn state(&self) -> WSState {
+        self.shared.lock().unwrap().state
+    }
+
+    // Reply to pings with pongs automatically (on by default)
+    pub fn set_auto_pong(&mut self, enabled: bool) {
+        self.auto_pong = enabled;
+    }
+
+    // Reads next frame, echoing peer's close frame if connection is open
+    pub fn read_message(&mut self) -> WSResult<WSMessage> {
+        if self.state() == WSState::Closed {
+            return Err(WSError::Closed);
+        }
+
+        let res = self.read_frame();
+        let msg = try!(self.check(res));
+        try!(self.process(&msg));
+        Ok(msg)
+    }
+
+    pub fn iter(&mut self) -> WSReaderMessages<S> {
+        WSReaderMessages { reader: self }
+    }
+
+    fn read_frame(&mut self) -> WSResult<WSMessage> {
+        let keepalive = self.keepalive;
+        let stream = match self.stream {
+            Some(ref mut s) => s,
+            None => return Err(WSError::NotConnected)
+        };
+
+        let mut buf = [0u8; 14];
+        try!(read_bytes(stream, &mut buf[..2], keepalive));
+        let size = frame::header_size(&[buf[0], buf[1]]);
+        try!(read_bytes(stream, &mut buf[2..size], keepalive));
+
+        let frame: FrameHeader = try!(self.shared.lock().unwrap().codec.decode_header(&buf[..size])).unwrap();
+
+        let mut payload = vec![0u8; frame.len as usize];
+        try!(read_bytes(stream, &mut *payload, keepalive));
+        self.shared.lock().unwrap().codec.decode_payload(&frame, payload)
+    }
+
+    // Handles failures: connection is either aborted or failed with error's close status
+    fn check<T>(&mut self, res: WSResult<T>) -> WSResult<T> {
+        match res {
+            Err(WSError::Io(e)) => {
+                let mut shared = self.shared.lock().unwrap();
+                shared.state = WSState::Closed;
+                shared.close_status = Some(WSStatusCode::Aborted);
+                Err(WSError::Io(e))
+            },
+            Err(e) => {
+                // Both handles are dropped for connection to be closed
+                if let Some(status) = e.status() {
+                    self.shared.lock().unwrap().fail(status);
+                    self.stream = None;
+                }
+                Err(e)
+            },
+            ok => ok
+        }
+    }
+
+    fn process(&mut self, msg: &WSMessage) -> WSResult<()> {
+        let mut shared = self.shared.lock().unwrap();
+
+        if msg.is_ping() && self.auto_pong && shared.state == WSState::Open {
+            let pong = shared.automask(WSMessage::pong(&*msg.data));
+            try!(shared.write_frame(&pong));
+        }
+
+        if msg.is_close() {
+            shared.close_status = Some(msg.status.unwrap_or(WSStatusCode::NoCode));
+            shared.close_reason = Some(msg.to_string());
+
+            if shared.state == WSState::Open {
+                shared.state = WSState::Closing;
+                let reply = shared.automask(WSMessage { header: WS_FIN | WS_OPTERM, data: Vec::new(), status: msg.status });
+                try!(shared.write_frame(&reply));
+            }
+            shared.state = WSState::Closed;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Write> WSWriter<S> {
+    #[inline] pub fn state(&self) -> WSState {
+        self.shared.lock().unwrap().state
+    }
+
+    #[inline] pub fn close_status(&self) -> Option<WSStatusCode> {
+        self.shared.lock().unwrap().close_status
+    }
+
+    pub fn close_reason(&self) -> Option<String> {
+        self.shared.lock().unwrap().close_reason.clone()
+    }
+
+    // Sends frame, no data may be sent after close frame
+    pub fn send_message(&self, msg: &WSMessage) -> WSResult<()> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.state != WSState::Open {
+            return Err(WSError::Closed);
+        }
+
+        try!(shared.write_frame(msg));
+        if msg.is_close() {
+            shared.state = WSState::Closing;
+        }
+        Ok(())
+    }
+
+    // Starts closing handshake, reader gets peer's close frame
+    // and connection is closed then
+    pub fn close(&self, status: WSStatusCode, reason: &str) -> WSResult<()> {
+        let msg = self.shared.lock().unwrap().automask(WSMessage::close(status, reason.as_bytes()));
+        self.send_message(&msg)
+    }
+}
+
+impl<S> Clone for WSWriter<S> {
+    fn clone(&self) -> WSWriter<S> {
+        WSWriter { shared: self.shared.clone() }
+    }
+}
+
+pub struct WSReaderMessages<'a, S: 'a = NetworkStream> {
+    reader: &'a mut WSReader<S>
+}
+
+impl<'a, S: Read + Write> Iterator for WSReaderMessages<'a, S> {
+    type Item = WSMessage;
+    fn next(&mut self) -> Option<WSMessage> {
+        self.reader.read_message().ok()
+    }
+}
+
+// Fills buffer completely, failing if connection is closed before that.
+// With `keepalive` read timeouts are retried, as split connection sends no pings.
+fn read_bytes<R: Read>(r: &mut R, buf: &mut [u8], keepalive: bool) -> WSResult<()> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match r.read(&mut buf[pos..]) {
+            Ok(0) => return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed in the middle of frame", None))),
+            Ok(n) => pos += n,
+            Err(ref e) if keepalive && (e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut) => (),
+            Err(e) => return Err(WSError::Io(e))
+        }
+    }
+    Ok(())
+}
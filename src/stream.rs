@@ -1,30 +1,101 @@
-use openssl::ssl::{SslMethod, SslStream, SslContext};
 use std::net::TcpStream;
 use std::io::{Write, Read, self};
+use std::time::Duration;
 
+use error::{WSError, WSResult};
+use proxy::Proxy;
+use tls::TlsConfig;
+#[cfg(feature = "openssl")] use openssl_tls;
+#[cfg(feature = "rustls")] use rustls_tls;
+
+// TLS backend is chosen with cargo features: `openssl` (default) or `rustls`,
+// only one of them may be enabled. With `no-tls` only ws:// is supported.
 pub enum NetworkStream {
     Tcp(TcpStream),
-    Ssl(SslStream<TcpStream>)
+    #[cfg(feature = "openssl")] Ssl(openssl_tls::TlsStream),
+    #[cfg(feature = "rustls")] Rustls(rustls_tls::TlsStream)
 }
 
 impl NetworkStream {
-    pub fn connect(hostname: &str, use_ssl: bool) -> io::Result<NetworkStream> {
-        let sock = try!(TcpStream::connect(hostname));
+    #[inline] pub fn connect(hostname: &str, use_ssl: bool) -> WSResult<NetworkStream> {
+        NetworkStream::connect_with_options(hostname, use_ssl, &Proxy::Direct, &TlsConfig::new())
+    }
+
+    // TLS, if used, runs through proxy tunnel
+    pub fn connect_with_options(hostname: &str, use_ssl: bool, proxy: &Proxy, tls: &TlsConfig) -> WSResult<NetworkStream> {
+        let sock = try!(proxy.connect(hostname, use_ssl));
 
         if use_ssl {
-            let ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|_| io::Error::new(io::ErrorKind::Other, "ssl context creation error", None)));
-            Ok(NetworkStream::Ssl(try!(SslStream::new(&ctx, sock).map_err(|_| io::Error::new(io::ErrorKind::Other, "ssl connection error", None)))))
+            tls_connect(sock, hostname, tls)
         } else {
             Ok(NetworkStream::Tcp(sock))
         }
     }
+
+    // Maps error of the first read after connecting: server may reject client
+    // certificate after TLS handshake is done from client's side (TLS 1.3),
+    // which is reported as `WSError::ClientCertificate` then
+    pub fn read_error(&self, err: io::Error, tls: &TlsConfig) -> WSError {
+        match *self {
+            NetworkStream::Tcp(_) => WSError::Io(err),
+            #[cfg(feature = "openssl")] NetworkStream::Ssl(_) => openssl_tls::read_error(err, tls.client_identity.is_some()),
+            #[cfg(feature = "rustls")] NetworkStream::Rustls(_) => rustls_tls::read_error(err, tls.client_identity.is_some())
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            NetworkStream::Tcp(ref s) => s.set_read_timeout(timeout),
+            #[cfg(feature = "openssl")] NetworkStream::Ssl(ref s) => s.get_ref().set_read_timeout(timeout),
+            #[cfg(feature = "rustls")] NetworkStream::Rustls(ref s) => s.sock.set_read_timeout(timeout)
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn tls_connect(sock: TcpStream, hostname: &str, tls: &TlsConfig) -> WSResult<NetworkStream> {
+    rustls_tls::connect(sock, hostname, tls).map(NetworkStream::Rustls)
+}
+
+#[cfg(feature = "openssl")]
+fn tls_connect(sock: TcpStream, hostname: &str, tls: &TlsConfig) -> WSResult<NetworkStream> {
+    openssl_tls::connect(sock, hostname, tls).map(NetworkStream::Ssl)
+}
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+fn tls_connect(_: TcpStream, _: &str, _: &TlsConfig) -> WSResult<NetworkStream> {
+    Err(WSError::handshake("wss:// is not supported without TLS backend"))
+}
+
+// Streams which can be cloned into another handle of the same connection,
+// needed to read and write from different threads (see `WebSocket::split()`)
+pub trait TryClone: Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl TryClone for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl TryClone for NetworkStream {
+    fn try_clone(&self) -> io::Result<NetworkStream> {
+        match *self {
+            NetworkStream::Tcp(ref s) => s.try_clone().map(NetworkStream::Tcp),
+            // TLS session state can't be shared between handles
+            #[cfg(feature = "openssl")] NetworkStream::Ssl(_) => Err(io::Error::new(io::ErrorKind::Other, "TLS stream can't be cloned", None)),
+            #[cfg(feature = "rustls")] NetworkStream::Rustls(_) => Err(io::Error::new(io::ErrorKind::Other, "TLS stream can't be cloned", None))
+        }
+    }
 }
 
 impl Read for NetworkStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
             NetworkStream::Tcp(ref mut s) => s.read(buf),
-            NetworkStream::Ssl(ref mut s) => s.read(buf)
+            #[cfg(feature = "openssl")] NetworkStream::Ssl(ref mut s) => s.read(buf),
+            #[cfg(feature = "rustls")] NetworkStream::Rustls(ref mut s) => s.read(buf)
         }
     }
 }
@@ -33,14 +104,16 @@ impl Write for NetworkStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match *self {
             NetworkStream::Tcp(ref mut s) => s.write(buf),
-            NetworkStream::Ssl(ref mut s) => s.write(buf)
+            #[cfg(feature = "openssl")] NetworkStream::Ssl(ref mut s) => s.write(buf),
+            #[cfg(feature = "rustls")] NetworkStream::Rustls(ref mut s) => s.write(buf)
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             NetworkStream::Tcp(ref mut s) => s.flush(),
-            NetworkStream::Ssl(ref mut s) => s.flush()
+            #[cfg(feature = "openssl")] NetworkStream::Ssl(ref mut s) => s.flush(),
+            #[cfg(feature = "rustls")] NetworkStream::Rustls(ref mut s) => s.flush()
         }
     }
 }
@@ -0,0 +1,185 @@
+use std::path::{Path, PathBuf};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ascii::AsciiExt;
+use std::fmt;
+
+// Lowest TLS version to negotiate
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12
+}
+
+// Client certificate for mutual TLS
+#[derive(Clone)]
+pub enum ClientIdentity {
+    // PEM files with certificate chain (leaf first) and private key
+    Pem { cert_chain: PathBuf, key: PathBuf },
+    // PKCS#12 archive with certificate, chain and private key
+    Pkcs12 { path: PathBuf, password: String }
+}
+
+// Password is not shown
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientIdentity::Pem { ref cert_chain, ref key } => write!(f, "Pem {{ cert_chain: {:?}, key: {:?} }}", cert_chain, key),
+            ClientIdentity::Pkcs12 { ref path, .. } => write!(f, "Pkcs12 {{ path: {:?}, password: \"***\" }}", path)
+        }
+    }
+}
+
+// TLS settings for wss:// connections. By default server certificate is verified
+// against system CA store and host name, and SNI is sent. With rustls backend,
+// bundled Mozilla roots (webpki-roots) are trusted if system store can't be loaded.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub ca_file: Option<PathBuf>,
+    pub verify_hostname: bool,
+    // name to send in SNI and verify certificate against, host from URL by default
+    pub server_name: Option<String>,
+    pub sni: bool,
+    pub min_version: Option<TlsVersion>,
+    pub insecure: bool,
+    pub client_identity: Option<ClientIdentity>
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig {
+            ca_file: None,
+            verify_hostname: true,
+            server_name: None,
+            sni: true,
+            min_version: None,
+            insecure: false,
+            client_identity: None
+        }
+    }
+
+    // Trust CAs from PEM bundle in addition to system ones
+    pub fn ca_file<P: AsRef<Path>>(mut self, path: P) -> TlsConfig {
+        self.ca_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn verify_hostname(mut self, enabled: bool) -> TlsConfig {
+        self.verify_hostname = enabled;
+        self
+    }
+
+    pub fn server_name(mut self, name: &str) -> TlsConfig {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
+    pub fn sni(mut self, enabled: bool) -> TlsConfig {
+        self.sni = enabled;
+        self
+    }
+
+    pub fn min_version(mut self, version: TlsVersion) -> TlsConfig {
+        self.min_version = Some(version);
+        self
+    }
+
+    // Accept any certificate, for development only
+    pub fn insecure(mut self) -> TlsConfig {
+        self.insecure = true;
+        self
+    }
+
+    // Authenticate with client certificate, sent if server asks for it
+    pub fn client_cert_pem<P: AsRef<Path>, K: AsRef<Path>>(mut self, cert_chain: P, key: K) -> TlsConfig {
+        self.client_identity = Some(ClientIdentity::Pem { cert_chain: cert_chain.as_ref().to_path_buf(), key: key.as_ref().to_path_buf() });
+        self
+    }
+
+    pub fn client_pkcs12<P: AsRef<Path>>(mut self, path: P, password: &str) -> TlsConfig {
+        self.client_identity = Some(ClientIdentity::Pkcs12 { path: path.as_ref().to_path_buf(), password: password.to_string() });
+        self
+    }
+
+    // Name to verify and send in SNI for connection to `hostname` (host:port)
+    pub fn name_for<'a>(&'a self, hostname: &'a str) -> &'a str {
+        match self.server_name {
+            Some(ref name) => &**name,
+            None => hostname.rsplitn(2, ':').last().unwrap_or(hostname).trim_matches(|c| c == '[' || c == ']')
+        }
+    }
+}
+
+// Whether TLS alert peer sent during handshake means it didn't accept our certificate:
+// bad_certificate, unsupported_certificate, certificate_revoked, certificate_expired,
+// certificate_unknown, unknown_ca, access_denied or certificate_required (RFC 8446 section 6)
+pub fn is_cert_rejection(alert: u8) -> bool {
+    match alert {
+        42...46 | 48 | 49 | 116 => true,
+        _ => false
+    }
+}
+
+// SNI must not be sent for IP addresses
+pub fn is_ip_address(name: &str) -> bool {
+    ip_octets(name).is_some()
+}
+
+// Address in network byte order (4 or 16 bytes) as it's stored
+// in certificate's IP address SAN, None if `name` is not an IP address
+pub fn ip_octets(name: &str) -> Option<Vec<u8>> {
+    if let Ok(ip) = name.parse::<Ipv4Addr>() {
+        return Some(ip.octets().to_vec());
+    }
+    name.parse::<Ipv6Addr>().ok().map(|ip| ip.segments().iter().flat_map(|s| vec![(*s >> 8) as u8, *s as u8].into_iter()).collect())
+}
+
+// Matches certificate name against host name as per RFC 6125,
+// wildcard is allowed as the whole left-most label only
+pub fn hostname_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_right_matches('.');
+    let host = host.trim_right_matches('.');
+
+    if !pattern.starts_with("*.") {
+        return pattern.eq_ignore_ascii_case(host);
+    }
+
+    let suffix = &pattern[1..];
+    // wildcard must not match IP addresses or top-level domains
+    match host.find('.') {
+        Some(idx) if idx > 0 && !is_ip_address(host) && suffix[1..].contains('.') => host[idx..].eq_ignore_ascii_case(suffix),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TlsConfig, ip_octets, hostname_matches};
+
+    #[test]
+    fn ip_address_octets() {
+        assert_eq!(ip_octets("192.168.0.1"), Some(vec![192, 168, 0, 1]));
+        assert_eq!(ip_octets("::1"), Some(vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]));
+        assert_eq!(ip_octets("2001:db8::ff00"), Some(vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0]));
+        assert_eq!(ip_octets("example.com"), None);
+        assert_eq!(ip_octets("[::1]"), None);
+    }
+
+    #[test]
+    fn pkcs12_password_is_not_shown() {
+        let config = TlsConfig::new().client_pkcs12("client.p12", "s3cr3t");
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("client.p12"));
+        assert!(!debug.contains("s3cr3t"));
+    }
+
+    #[test]
+    fn wildcard_names() {
+        assert!(hostname_matches("*.example.com", "www.example.com"));
+        assert!(hostname_matches("WWW.example.com.", "www.EXAMPLE.com"));
+        assert!(!hostname_matches("*.example.com", "example.com"));
+        assert!(!hostname_matches("*.example.com", "a.b.example.com"));
+        assert!(!hostname_matches("*.com", "example.com"));
+        assert!(!hostname_matches("*.0.0.1", "127.0.0.1"));
+    }
+}
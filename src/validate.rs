@@ -0,0 +1,179 @@
+use std::str;
+
+use message::{WSHeader, WSStatusCode, WS_FIN, WS_RSV, WS_OPCODE, WS_OPCTRL, WS_MASK,
+              WS_OPCONT, WS_OPTEXT, WS_OPBIN, WS_OPTERM, WS_OPPING, WS_OPPONG};
+use error::{WSError, WSResult};
+
+// Validates frame header as per RFC 6455 section 5, before payload is read.
+// `server` tells which side reads the frame, `rsv` and `opcodes` are
+// reserved bits and opcodes claimed by negotiated extensions.
+pub fn check_header(header: WSHeader, len: u64, server: bool, rsv: WSHeader, opcodes: &[WSHeader]) -> WSResult<()> {
+    if header.contains(WS_MASK) != server {
+        return Err(WSError::protocol(WSStatusCode::ProtocolError,
+            if server { "client frames must be masked" } else { "server frames must not be masked" }));
+    }
+
+    if !rsv.contains(header & WS_RSV) {
+        return Err(WSError::protocol(WSStatusCode::ProtocolError, "reserved bits set without negotiated extension"));
+    }
+
+    let opcode = header & WS_OPCODE;
+    let known = opcode == WS_OPCONT || opcode == WS_OPTEXT || opcode == WS_OPBIN
+        || opcode == WS_OPTERM || opcode == WS_OPPING || opcode == WS_OPPONG;
+    if !known && !opcodes.contains(&opcode) {
+        return Err(WSError::protocol(WSStatusCode::ProtocolError, "unknown opcode"));
+    }
+
+    if header.contains(WS_OPCTRL) {
+        if !header.contains(WS_FIN) {
+            return Err(WSError::protocol(WSStatusCode::ProtocolError, "fragmented control frame"));
+        }
+        if len > 125 {
+            return Err(WSError::protocol(WSStatusCode::ProtocolError, "control frame payload is longer than 125 bytes"));
+        }
+        if opcode == WS_OPTERM && len == 1 {
+            return Err(WSError::protocol(WSStatusCode::ProtocolError, "truncated close frame status code"));
+        }
+    }
+
+    Ok(())
+}
+
+// Only codes defined by RFC 6455 section 7.4.1 and application codes may be sent
+pub fn check_close_code(code: u16) -> WSResult<()> {
+    match code {
+        1000...1003 | 1007...1011 | 3000...4999 => Ok(()),
+        _ => Err(WSError::protocol(WSStatusCode::ProtocolError, "invalid close status code"))
+    }
+}
+
+// Checks data frame sequence: continuation frames must follow a non-final
+// data frame, and no new data message may start before the last one is finished
+pub fn check_sequence(fragmented: Option<WSHeader>, header: WSHeader) -> WSResult<Option<WSHeader>> {
+    if header.contains(WS_OPCTRL) {
+        return Ok(fragmented);
+    }
+
+    let opcode = header & WS_OPCODE;
+    match (fragmented, opcode == WS_OPCONT) {
+        (None, true) => Err(WSError::protocol(WSStatusCode::ProtocolError, "continuation frame without message start")),
+        (Some(_), false) => Err(WSError::protocol(WSStatusCode::ProtocolError, "new message started before previous one finished")),
+        (Some(op), true) => Ok(if header.contains(WS_FIN) { None } else { Some(op) }),
+        (None, false) => Ok(if header.contains(WS_FIN) { None } else { Some(opcode) })
+    }
+}
+
+// Incremental UTF-8 validator for text messages split into fragments,
+// code points may span fragment boundaries
+pub struct Utf8Validator {
+    tail: Vec<u8>
+}
+
+impl Utf8Validator {
+    pub fn new() -> Utf8Validator {
+        Utf8Validator { tail: Vec::new() }
+    }
+
+    pub fn feed(&mut self, data: &[u8], last: bool) -> WSResult<()> {
+        let mut buf = Vec::with_capacity(self.tail.len() + data.len());
+        buf.push_all(&*self.tail);
+        buf.push_all(data);
+
+        let cut = buf.len() - if last { 0 } else { incomplete_tail(&*buf) };
+        if str::from_utf8(&buf[..cut]).is_err() {
+            self.tail.clear();
+            return Err(WSError::protocol(WSStatusCode::InvalidData, "invalid UTF-8 in text message"));
+        }
+
+        self.tail = buf[cut..].to_vec();
+        Ok(())
+    }
+}
+
+// Number of trailing bytes which start a multibyte sequence not finished yet
+fn incomplete_tail(data: &[u8]) -> usize {
+    for n in 1..4 {
+        if data.len() < n {
+            break;
+        }
+        let b = data[data.len() - n];
+        if b & 0xc0 == 0x80 {
+            continue; // continuation byte, look further back
+        }
+        let need = if b & 0xe0 == 0xc0 { 2 } else if b & 0xf0 == 0xe0 { 3 } else if b & 0xf8 == 0xf0 { 4 } else { 1 };
+        return if need > n { n } else { 0 };
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Utf8Validator;
+
+    #[test]
+    fn code_point_split_across_fragments() {
+        // U+20AC is e2 82 ac
+        let mut v = Utf8Validator::new();
+        assert!(v.feed(b"price: \xe2", false).is_ok());
+        assert!(v.feed(b"\x82", false).is_ok());
+        assert!(v.feed(b"\xac", true).is_ok());
+    }
+
+    #[test]
+    fn four_byte_code_point_split_at_every_position() {
+        // U+1F600 is f0 9f 98 80
+        let bytes = b"a\xf0\x9f\x98\x80b";
+        for cut in 1..bytes.len() {
+            let mut v = Utf8Validator::new();
+            assert!(v.feed(&bytes[..cut], false).is_ok(), "first part up to {}", cut);
+            assert!(v.feed(&bytes[cut..], true).is_ok(), "second part from {}", cut);
+        }
+    }
+
+    #[test]
+    fn code_point_split_into_single_byte_fragments() {
+        let mut v = Utf8Validator::new();
+        for b in b"\xf0\x9f\x98".iter() {
+            assert!(v.feed(&[*b], false).is_ok());
+        }
+        assert!(v.feed(b"\x80", true).is_ok());
+    }
+
+    #[test]
+    fn empty_fragments() {
+        let mut v = Utf8Validator::new();
+        assert!(v.feed(b"\xe2\x82", false).is_ok());
+        assert!(v.feed(b"", false).is_ok());
+        assert!(v.feed(b"\xac", false).is_ok());
+        assert!(v.feed(b"", true).is_ok());
+    }
+
+    #[test]
+    fn truncated_code_point_at_message_end() {
+        let mut v = Utf8Validator::new();
+        assert!(v.feed(b"ok \xe2\x82", false).is_ok());
+        assert!(v.feed(b"", true).is_err());
+    }
+
+    #[test]
+    fn invalid_continuation_in_next_fragment() {
+        let mut v = Utf8Validator::new();
+        assert!(v.feed(b"\xe2", false).is_ok());
+        assert!(v.feed(b"A", true).is_err());
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        assert!(Utf8Validator::new().feed(b"\xff", false).is_err());
+        assert!(Utf8Validator::new().feed(b"\xc0\xaf", true).is_err());
+        // surrogates are not valid in UTF-8
+        assert!(Utf8Validator::new().feed(b"\xed\xa0\x80", true).is_err());
+    }
+
+    #[test]
+    fn next_message_after_valid_one() {
+        let mut v = Utf8Validator::new();
+        assert!(v.feed(b"\xe2\x82\xac", true).is_ok());
+        assert!(v.feed(b"\x82", true).is_err());
+    }
+}